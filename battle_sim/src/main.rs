@@ -0,0 +1,325 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use clap::*;
+use dt_lib::{
+    battle::{
+        ai::choose_action,
+        army::{Army, ArmyStats, TroopType},
+        battlefield::{handle_action, move_thing, Action, BattleInfo},
+        control::{Control, PC_ControlSetings},
+        troop::Troop,
+    },
+    parse::{parse_items, parse_units},
+    units::unit::Unit,
+};
+use rand::{rngs::StdRng, seq::IteratorRandom};
+use serde::Serialize;
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum Controller {
+    /// Picks a random possible action
+    Random,
+    /// Uses the battle AI
+    Ai,
+}
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum Format {
+    Csv,
+    Json,
+}
+#[derive(Parser)]
+/// Plays battles between two armies without UI and prints statistics
+struct Args {
+    /// An INI file with [Army1] and [Army2] sections
+    #[arg()]
+    armies: PathBuf,
+    /// How many battles to play
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    battles: usize,
+    /// Controller of the first army
+    #[arg(long, value_enum, default_value_t = Controller::Ai)]
+    army1: Controller,
+    /// Controller of the second army
+    #[arg(long, value_enum, default_value_t = Controller::Ai)]
+    army2: Controller,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+    /// Units file
+    #[arg(long, default_value = "Units.ini")]
+    units: String,
+    /// Artefacts file
+    #[arg(long, default_value = "Rus_Artefacts.ini")]
+    items: String,
+    /// Language of the artefacts file
+    #[arg(long, default_value = "Rus")]
+    lang: String,
+}
+
+/// Army as it is written in the armies file
+struct ArmyTemplate {
+    name: String,
+    /// Unit index and its level
+    troops: Vec<(usize, u64)>,
+    aggression: Option<u8>,
+}
+impl ArmyTemplate {
+    fn build(&self, army: usize, units: &Vec<Unit>) -> Army {
+        let mut new_army = Army::new(
+            Vec::new(),
+            ArmyStats {
+                army_name: self.name.clone(),
+                ..Default::default()
+            },
+            Vec::new(),
+            (0, 0),
+            true,
+            Control::PC,
+        );
+        new_army.pc_settings = self.aggression.map(|aggression| PC_ControlSetings {
+            aggression,
+            ..Default::default()
+        });
+        for &(unit, lvl) in &self.troops {
+            let mut unit = units[unit].clone();
+            let name = unit.info.name.clone();
+            unit.army = army;
+            while unit.lvl.lvl < lvl && unit.lvl.max_xp > 0 {
+                let needed = unit.lvl.max_xp - unit.lvl.xp;
+                unit.add_xp(needed);
+            }
+            if new_army.add_troop(Troop::new(unit).into()).is_err() {
+                eprintln!("{name} does not fit into {}", self.name);
+            }
+        }
+        new_army
+    }
+}
+
+fn unit_index(name: &str, units: &Vec<Unit>) -> Option<usize> {
+    name.parse::<usize>()
+        .ok()
+        .filter(|index| *index < units.len())
+        .or_else(|| units.iter().position(|unit| unit.info.name == name))
+}
+fn parse_armies(path: &PathBuf, units: &Vec<Unit>) -> Result<[ArmyTemplate; 2], String> {
+    let doc = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let mut armies = [None, None];
+    for (sec, props) in advini::parse_for_sections(&doc) {
+        let slot = match &*sec {
+            "Army1" => 0,
+            "Army2" => 1,
+            _ => continue,
+        };
+        let mut troops = Vec::new();
+        if let Some(list) = props.get("troops") {
+            for troop in list.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+                let (name, lvl) = troop.split_once(';').unwrap_or((troop, "0"));
+                let unit = unit_index(name.trim(), units)
+                    .ok_or_else(|| format!("Unknown unit {name} in {sec}"))?;
+                let lvl = lvl
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| format!("Wrong level of {name} in {sec}"))?;
+                troops.push((unit, lvl));
+            }
+        }
+        let aggression = match props.get("aggression") {
+            Some(v) => Some(
+                v.parse::<u8>()
+                    .map_err(|_| format!("Wrong aggression in {sec}"))?,
+            ),
+            None => None,
+        };
+        armies[slot] = Some(ArmyTemplate {
+            name: props.get("name").cloned().unwrap_or(sec),
+            troops,
+            aggression,
+        });
+    }
+    match armies {
+        [Some(army1), Some(army2)] => Ok([army1, army2]),
+        _ => Err("Both [Army1] and [Army2] sections are required".into()),
+    }
+}
+
+/// Random possible action, `rng` comes from `BattleInfo::rng` so the battle can be replayed
+fn random_action(battle: &BattleInfo, armys: &Vec<Army>, rng: &mut StdRng) -> Option<Action> {
+    let active_unit = battle.active_unit?;
+    let target = battle
+        .can_interact
+        .as_ref()
+        .and_then(|interactions| interactions.iter().choose(rng).copied());
+    Some(match target {
+        Some((army, index)) => Action::Cell(index, battle.slot(army)?),
+        None => Action::Cell(
+            armys[active_unit.0].troops[active_unit.1].get().pos.into(),
+            battle.slot(active_unit.0)?,
+        ),
+    })
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct UnitStats {
+    fielded: u64,
+    kills: u64,
+    deaths: u64,
+}
+#[derive(Clone, Debug, Default, Serialize)]
+struct SideStats {
+    name: String,
+    controller: String,
+    wins: u64,
+    win_rate: f64,
+    units: BTreeMap<String, UnitStats>,
+}
+#[derive(Clone, Debug, Default, Serialize)]
+struct Report {
+    battles: u64,
+    average_moves: f64,
+    sides: Vec<SideStats>,
+}
+
+fn troop_name(troop: &TroopType) -> String {
+    troop.get().unit.info.name.clone()
+}
+
+/// Plays one battle, kills are credited to the unit that made the action
+fn play_battle(armys: &mut Vec<Army>, controllers: [Controller; 2], report: &mut Report) {
+    for (side, army) in armys.iter().enumerate() {
+        for troop in &army.troops {
+            report.sides[side]
+                .units
+                .entry(troop_name(troop))
+                .or_default()
+                .fielded += 1;
+        }
+    }
+    let mut battle = BattleInfo::new(armys, 0, 1);
+    let mut rng = battle.rng();
+    while battle.winner.is_none() {
+        let Some(active_unit) = battle.active_unit else {
+            move_thing(&mut battle, armys);
+            continue;
+        };
+        let side = battle.side(active_unit.0);
+        let actor = troop_name(&armys[active_unit.0].troops[active_unit.1]);
+        // Clones share the troops, so they show who died even after corpses are moved
+        let alive = armys
+            .iter()
+            .flat_map(|army| army.troops.iter().cloned())
+            .filter(|troop| !troop.get().is_dead())
+            .collect::<Vec<_>>();
+        let action = match controllers[side] {
+            Controller::Random => random_action(&battle, armys, &mut rng),
+            Controller::Ai => choose_action(&battle, armys),
+        };
+        if let Some(action) = action {
+            handle_action(action, &mut battle, armys);
+        }
+        move_thing(&mut battle, armys);
+        for troop in alive {
+            let (army, name) = {
+                let troop = troop.get();
+                if !troop.is_dead() {
+                    continue;
+                }
+                (troop.unit.army, troop.unit.info.name.clone())
+            };
+            let dead_side = battle.side(army);
+            report.sides[dead_side]
+                .units
+                .entry(name)
+                .or_default()
+                .deaths += 1;
+            if dead_side != side {
+                report.sides[side]
+                    .units
+                    .entry(actor.clone())
+                    .or_default()
+                    .kills += 1;
+            }
+        }
+    }
+    if let Some(winner) = battle.winner {
+        report.sides[battle.side(winner)].wins += 1;
+    }
+    report.battles += 1;
+    report.average_moves += battle.move_count as f64;
+}
+
+fn print_csv(report: &Report) {
+    println!("battles,average_moves,army1_wins,army1_win_rate,army2_wins,army2_win_rate");
+    println!(
+        "{},{:.2},{},{:.4},{},{:.4}",
+        report.battles,
+        report.average_moves,
+        report.sides[0].wins,
+        report.sides[0].win_rate,
+        report.sides[1].wins,
+        report.sides[1].win_rate
+    );
+    println!();
+    println!("army,unit,fielded,kills,deaths");
+    for side in &report.sides {
+        for (unit, stats) in &side.units {
+            println!(
+                "{},{},{},{},{}",
+                side.name, unit, stats.fielded, stats.kills, stats.deaths
+            );
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let units = match parse_units(Some(args.units.as_str())) {
+        Ok((units, _)) => units,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    parse_items(Some(args.items.as_str()), &args.lang);
+    let templates = match parse_armies(&args.armies, &units) {
+        Ok(templates) => templates,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+    let controllers = [args.army1, args.army2];
+    let mut report = Report {
+        sides: templates
+            .iter()
+            .zip(controllers)
+            .map(|(template, controller)| SideStats {
+                name: template.name.clone(),
+                controller: format!("{controller:?}"),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    for _ in 0..args.battles {
+        let mut armys = templates
+            .iter()
+            .enumerate()
+            .map(|(army, template)| template.build(army, &units))
+            .collect::<Vec<_>>();
+        play_battle(&mut armys, controllers, &mut report);
+    }
+    if report.battles > 0 {
+        report.average_moves /= report.battles as f64;
+        for side in &mut report.sides {
+            side.win_rate = side.wins as f64 / report.battles as f64;
+        }
+    }
+    match args.format {
+        Format::Csv => print_csv(&report),
+        Format::Json => match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{json}"),
+            Err(err) => eprintln!("{err}"),
+        },
+    }
+}
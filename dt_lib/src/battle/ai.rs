@@ -0,0 +1,209 @@
+use crate::{
+    battle::{
+        army::{Army, MAX_TROOPS},
+        battlefield::{
+            attack_cells, field_type, handle_action, move_thing, troop_inactive, Action,
+            BattleInfo, Field,
+        },
+        control::Control,
+        event::BattleEvent,
+        experience::BattleSummary,
+    },
+    units::unit::{calclate_unit_power, ActionResult, MagicDirection, MagicType, Unit, UnitPos},
+};
+
+/// Aggression used for armies that have no PC settings attached
+pub const DEFAULT_AGGRESSION: u8 = 128;
+/// Steps `auto_resolve` makes before it ends a stuck battle by timeout
+const AUTO_RESOLVE_STEPS: usize = 100_000;
+
+/// What the AI thinks about one possible action of the active unit
+#[derive(Clone, Debug)]
+pub struct ScoredAction {
+    pub army: usize,
+    pub index: usize,
+    pub score: f32,
+    pub result: Option<ActionResult>,
+}
+
+pub fn army_aggression(army: &Army) -> u8 {
+    army.pc_settings
+        .as_ref()
+        .map(|settings| settings.aggression)
+        .unwrap_or(DEFAULT_AGGRESSION)
+}
+pub fn is_ai_controlled(army: &Army) -> bool {
+    matches!(army.control, Control::PC)
+}
+fn supports_allies(unit: &Unit) -> bool {
+    match unit.info.magic_type {
+        Some(MagicType::Life(direction))
+        | Some(MagicType::Death(direction))
+        | Some(MagicType::Elemental(direction)) => matches!(
+            direction,
+            MagicDirection::ToAlly
+                | MagicDirection::ToAll
+                | MagicDirection::BlessOnly
+                | MagicDirection::CureOnly
+        ),
+        None => false,
+    }
+}
+
+/// Scores the interaction by its preview, so nothing in the battle is touched
+fn score_interaction(
+    me: &Unit,
+    my_pos: UnitPos,
+    target: &Unit,
+    target_pos: UnitPos,
+    battle: &BattleInfo,
+    aggression: f32,
+) -> (f32, Option<ActionResult>) {
+    let Some(preview) = me.preview_attack(target, target_pos, my_pos, battle) else {
+        return (0., None);
+    };
+    let target_power = calclate_unit_power(target);
+    let my_power = calclate_unit_power(me);
+    let target_max_hp = target.modified.max_hp.max(1) as f32;
+    let my_max_hp = me.modified.max_hp.max(1) as f32;
+    let my_loss = preview.attacker_hp_lost as f32 / my_max_hp * my_power;
+
+    let score = if me.army != target.army {
+        let mut score = preview.hp_lost as f32 / target_max_hp * target_power;
+        if preview.target_dies {
+            score += target_power;
+        }
+        if !preview.effects.is_empty() {
+            score += target_power * 0.3;
+        }
+        (score - my_loss) * (0.5 + aggression)
+    } else {
+        let mut score = preview.healed as f32 / target_max_hp * target_power;
+        if !preview.effects.is_empty() {
+            score += target_power * 0.25;
+        }
+        score * (1.5 - aggression)
+    };
+    (score, Some(preview.result))
+}
+
+/// Collects every interaction of the active unit from `BattleInfo::can_interact` with its score
+pub fn score_interactions(battle: &BattleInfo, armys: &Vec<Army>) -> Vec<ScoredAction> {
+    let (Some(active_unit), Some(can_interact)) = (battle.active_unit, &battle.can_interact)
+    else {
+        return Vec::new();
+    };
+    let aggression = army_aggression(&armys[active_unit.0]) as f32 / u8::MAX as f32;
+    let me = armys[active_unit.0].troops[active_unit.1].get().clone();
+    can_interact
+        .iter()
+        .filter_map(|&(army, index)| {
+            let target = armys[army].get_troop(index)?.get().clone();
+            if target.is_dead() || (target.unit.army == me.unit.army && !supports_allies(&me.unit))
+            {
+                return None;
+            }
+            let (my_pos, target_pos) = attack_cells(&me, &target, Some(index))?;
+            let (score, result) = score_interaction(
+                &me.unit,
+                my_pos,
+                &target.unit,
+                target_pos,
+                battle,
+                aggression,
+            );
+            if result.is_none() {
+                return None;
+            }
+            Some(ScoredAction {
+                army,
+                index,
+                score,
+                result,
+            })
+        })
+        .collect()
+}
+
+/// Finds a free front row cell for a melee unit standing behind, closest to its column
+fn front_row_cell(army: &Army, troop_index: usize) -> Option<usize> {
+    let troop = army.troops[troop_index].get();
+    let damage = troop.unit.modified.damage;
+    if damage.hand == 0 || damage.ranged > 0 {
+        return None;
+    }
+    let max_troops = *MAX_TROOPS;
+    if field_type(troop.pos.into(), max_troops) != Field::Back {
+        return None;
+    }
+    let size = troop.unit.info.size;
+    (0..max_troops)
+        .filter(|&index| field_type(index, max_troops) == Field::Front)
+        .filter(|&index| army.fits_instead(troop_index, size, index))
+        .min_by_key(|&index| (UnitPos::from_index(index).0 as i64 - troop.pos.0 as i64).abs())
+}
+
+/// Picks an action for the active unit. Returns None when there is no one to act.
+pub fn choose_action(battle: &BattleInfo, armys: &Vec<Army>) -> Option<Action> {
+    if battle.winner.is_some() {
+        return None;
+    }
+    let active_unit = battle.active_unit?;
+    if troop_inactive(&armys[active_unit.0].troops[active_unit.1].get()) {
+        return None;
+    }
+    let aggression = army_aggression(&armys[active_unit.0]) as f32 / u8::MAX as f32;
+    let best = score_interactions(battle, armys)
+        .into_iter()
+        .filter(|action| action.score > 0.)
+        .max_by(|a, b| a.score.total_cmp(&b.score));
+
+    let army = &armys[active_unit.0];
+    if let Some(to) = front_row_cell(army, active_unit.1) {
+        let my_power = calclate_unit_power(&army.troops[active_unit.1].get().unit);
+        let move_score = my_power * 0.5 * (0.5 + aggression);
+        if best.as_ref().map_or(true, |best| best.score < move_score) {
+            return Some(Action::Move(active_unit.0, active_unit.1, to));
+        }
+    }
+    if let Some(best) = best {
+        return Some(Action::Cell(best.index, battle.slot(best.army)?));
+    }
+    // Nothing useful to do, so the unit skips its move
+    let pos = army.troops[active_unit.1].get().pos.into();
+    Some(Action::Cell(pos, battle.slot(active_unit.0)?))
+}
+
+/// Makes a move for the active unit if its army is controlled by PC
+pub fn ai_move(
+    battle: &mut BattleInfo,
+    armys: &mut Vec<Army>,
+) -> Option<(ActionResult, (usize, usize))> {
+    let active_unit = battle.active_unit?;
+    if !is_ai_controlled(&armys[active_unit.0]) {
+        return None;
+    }
+    let action = choose_action(battle, armys)?;
+    handle_action(action, battle, armys)
+}
+
+/// Plays the whole battle with the battle AI on every side, whoever controls the armies,
+/// and ends it. For fights nobody watches, like PC against PC or quick combat.
+/// A battle that makes no progress in `AUTO_RESOLVE_STEPS` is won by the timeout winner
+pub fn auto_resolve(battle: &mut BattleInfo, armys: &mut Vec<Army>) -> BattleSummary {
+    for _ in 0..AUTO_RESOLVE_STEPS {
+        if battle.winner.is_some() {
+            break;
+        }
+        if let Some(action) = choose_action(battle, armys) {
+            handle_action(action, battle, armys);
+        }
+        move_thing(battle, armys);
+    }
+    if battle.winner.is_none() {
+        let winner = battle.leader(battle.rules.timeout_winner);
+        battle.winner = Some(winner);
+        battle.emit(BattleEvent::Winner { army: winner });
+    }
+    battle.end(armys)
+}
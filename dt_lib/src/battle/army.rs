@@ -1,6 +1,7 @@
 use crate::{
     battle::{
         battlefield::{field_type, Field},
+        rules::BATTLE_SETTINGS,
         troop::Troop,
     },
     items::item::Item,
@@ -11,15 +12,17 @@ use crate::{
     },
     mutrc::SendMut,
     parse::SETTINGS,
-    units::unit::UnitPos,
+    time::time::Time,
+    units::unit::{Unit, UnitPos},
 };
 use advini::{Ini, IniParseError, Section, SectionError, Sections};
 use alkahest::{alkahest, private::*};
+use math_thingies::Percent;
 use num::{integer::sqrt, pow};
 use once_cell::sync::Lazy;
 use pathfinding::directed::astar::astar;
 
-use super::control::{Control, PC_ControlSetings};
+use super::control::{Control, PC_ControlSetings, ENEMY_GROUP, PLAYER_GROUP};
 #[derive(Clone, Debug, Default, Sections)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct ArmyStats {
@@ -48,7 +51,34 @@ fn eq_fields(index: usize, index1: usize, columns: usize, rows: usize) -> bool {
     ) || matches!((start_field, this_field), (Field::Reserve, Field::Reserve))
 }
 
-pub const MAX_LINES: usize = 2;
+/// Lines of the battlefield, see `BattleSettings::max_lines`
+pub static MAX_LINES: Lazy<usize> = Lazy::new(|| BATTLE_SETTINGS.max_lines.max(1));
+
+/// Preset of where new troops of the army are placed, see `Army::add_troop`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub enum Formation {
+    /// First free cell, the reserve included
+    #[default]
+    FirstFree,
+    /// Shooters and casters in the back line, the rest in the front one
+    RangedBack,
+    /// Everybody in the front line while it has room, the reserve last
+    FrontFirst,
+}
+impl Formation {
+    /// How much the formation wants the unit on the cell, lower is better
+    fn rank(self, unit: &Unit, index: usize) -> usize {
+        let field = field_type(index, *MAX_TROOPS);
+        let shooter = unit.modified.damage.ranged > 0 || unit.info.magic_type.is_some();
+        match self {
+            Formation::FirstFree => 0,
+            _ if field == Field::Reserve => 2,
+            Formation::RangedBack => (shooter != (field == Field::Back)) as usize,
+            Formation::FrontFirst => (field != Field::Front) as usize,
+        }
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 //#[alkahest(Formula)]
@@ -68,8 +98,14 @@ pub struct Army {
     pub active: bool,
     //#[unused]
     pub defeated: bool,
+    /// The army retreated from its last battle
+    pub fled: bool,
     //#[default_value = "Control::PC"]
     pub control: Control,
+    /// Group of the army, see `Relations::towards`
+    pub group: usize,
+    /// Where hired troops are placed, see `Army::set_formation`
+    pub formation: Formation,
 	pub pc_settings: Option<PC_ControlSetings>,
     //#[unused]
     pub path: Vec<(usize, usize)>,
@@ -107,8 +143,14 @@ impl Army {
             building: None,
             hitmap,
             defeated: false,
+            fled: false,
             stats,
+            group: match control {
+                Control::Player(_) => PLAYER_GROUP,
+                Control::PC => ENEMY_GROUP,
+            },
             control,
+            formation: Formation::default(),
             inventory,
             pos,
             active,
@@ -119,6 +161,17 @@ impl Army {
         }
         army
     }
+    /// Clone that does not share troops with the original army
+    pub fn deep_clone(&self) -> Self {
+        Self {
+            troops: self
+                .troops
+                .iter()
+                .map(|troop| SendMut::new(troop.get().clone()))
+                .collect(),
+            ..self.clone()
+        }
+    }
     pub fn recalc_hitmap(troops: &Vec<TroopType>, hitmap: &mut Vec<Option<usize>>, columns: usize) {
         let info = troops.iter().enumerate().map(|(num, troop)| {
             let troop = troop.get();
@@ -137,7 +190,7 @@ impl Army {
         for _ in 0..*MAX_TROOPS {
             hitmap.push(None);
         }
-        Army::recalc_hitmap(&self.troops, &mut hitmap, *MAX_TROOPS / MAX_LINES);
+        Army::recalc_hitmap(&self.troops, &mut hitmap, *MAX_TROOPS / *MAX_LINES);
         self.hitmap = hitmap
     }
     pub fn get_army_slice<'a>(troops: &'a mut Vec<Troop>) -> Vec<&'a mut [Troop]> {
@@ -213,16 +266,210 @@ impl Army {
         possible
     }
 
+    /// Places the troop where the formation of the army wants it
     pub fn add_troop(&mut self, wrap_troop: TroopType) -> Result<(), ()> {
-        let size = wrap_troop.get().unit.info.size;
-        let res = Army::fit(&self.hitmap, size, MAX_LINES, *MAX_TROOPS / MAX_LINES);
-        let pos = res.first().ok_or(())?;
-        wrap_troop.get().pos = UnitPos::from_index(*pos);
+        let pos = self
+            .formation_cell(&self.hitmap, &wrap_troop.get().unit, |_| true)
+            .ok_or(())?;
+        wrap_troop.get().pos = UnitPos::from_index(pos);
         self.troops.push(wrap_troop);
         self.recalc_army_hitmap();
         Ok(())
     }
+    /// Whether the army has room for the unit, see `Army::add_troop`
+    pub fn can_fit(&self, unit: &Unit) -> bool {
+        self.formation_cell(&self.hitmap, unit, |_| true).is_some()
+    }
+    /// Free cell of the hitmap the formation prefers for the unit among the allowed ones
+    fn formation_cell(
+        &self,
+        hitmap: &[Option<usize>],
+        unit: &Unit,
+        allowed: impl Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let size = unit.info.size;
+        Army::fit(hitmap, size, *MAX_LINES, *MAX_TROOPS / *MAX_LINES)
+            .into_iter()
+            .filter(|index| allowed(*index))
+            .min_by_key(|index| self.formation.rank(unit, *index))
+    }
+    /// Saves the formation and places every troop again by it.
+    /// Nothing changes if the troops do not fit that way
+    pub fn set_formation(&mut self, formation: Formation) -> Result<(), ()> {
+        let old = std::mem::replace(&mut self.formation, formation);
+        let mut hitmap = vec![None; *MAX_TROOPS];
+        let mut places = Vec::with_capacity(self.troops.len());
+        for (index, troop) in self.troops.iter().enumerate() {
+            let troop = troop.get();
+            let unit = &troop.unit;
+            let Some(to) = self.formation_cell(&hitmap, unit, |_| true) else {
+                self.formation = old;
+                return Err(());
+            };
+            Army::occupy(&mut hitmap, unit.info.size, to, index);
+            places.push(to);
+        }
+        for (troop, to) in self.troops.iter().zip(places) {
+            troop.get().pos = UnitPos::from_index(to);
+        }
+        self.recalc_army_hitmap();
+        Ok(())
+    }
+    /// Marks the cells a troop of `size` covers from the cell
+    fn occupy(hitmap: &mut [Option<usize>], size: (usize, usize), at: usize, troop: usize) {
+        let columns = *MAX_TROOPS / *MAX_LINES;
+        let pos = UnitPos::from_index(at);
+        for j in 0..size.1 {
+            for i in 0..size.0 {
+                hitmap[(pos.1 + j) * columns + pos.0 + i] = Some(troop);
+            }
+        }
+    }
 
+    /// Hitmap of the army as if the troop was not there
+    pub fn hitmap_without(&self, troop: usize) -> Vec<Option<usize>> {
+        self.hitmap
+            .iter()
+            .map(|cell| cell.filter(|index| *index != troop))
+            .collect()
+    }
+    /// Whether a troop of `size` fits at the cell of the hitmap
+    fn fits_at(hitmap: &[Option<usize>], size: (usize, usize), to: usize) -> bool {
+        let columns = *MAX_TROOPS / *MAX_LINES;
+        let pos = UnitPos::from_index(to);
+        to < *MAX_TROOPS
+            && pos.0 + size.0 <= columns
+            && pos.1 + size.1 <= *MAX_LINES
+            && Army::fit_to(hitmap, size, columns, *MAX_LINES, pos.1, pos.0)
+    }
+    /// Whether a troop of `size` fits at the cell in place of the troop,
+    /// the cells the troop covers now count as free
+    pub fn fits_instead(&self, troop: usize, size: (usize, usize), to: usize) -> bool {
+        Army::fits_at(&self.hitmap_without(troop), size, to)
+    }
+    /// Whether the whole troop fits when moved to the cell
+    pub fn can_move_troop(&self, troop: usize, to: usize) -> bool {
+        let Some(size) = self
+            .troops
+            .get(troop)
+            .map(|troop| troop.get().unit.info.size)
+        else {
+            return false;
+        };
+        self.fits_instead(troop, size, to)
+    }
+    /// Moves the troop to the cell if the whole troop fits there
+    pub fn move_troop(&mut self, troop: usize, to: usize) -> Result<(), ()> {
+        if !self.can_move_troop(troop, to) {
+            return Err(());
+        }
+        self.troops[troop].get().pos = UnitPos::from_index(to);
+        self.recalc_army_hitmap();
+        Ok(())
+    }
+    /// Swaps the places of two troops, if each of them fits at the place of the other
+    pub fn swap_troops(&mut self, troop1: usize, troop2: usize) -> Result<(), ()> {
+        let place = |troop: usize| {
+            let troop = self.troops.get(troop)?.get();
+            let pos: usize = troop.pos.into();
+            Some((pos, troop.unit.info.size))
+        };
+        let ((pos1, size1), (pos2, size2)) = (place(troop1).ok_or(())?, place(troop2).ok_or(())?);
+        if troop1 == troop2 {
+            return Ok(());
+        }
+        let mut hitmap: Vec<_> = self
+            .hitmap
+            .iter()
+            .map(|cell| cell.filter(|index| *index != troop1 && *index != troop2))
+            .collect();
+        if !Army::fits_at(&hitmap, size1, pos2) {
+            return Err(());
+        }
+        Army::occupy(&mut hitmap, size1, pos2, troop1);
+        if !Army::fits_at(&hitmap, size2, pos1) {
+            return Err(());
+        }
+        self.troops[troop1].get().pos = UnitPos::from_index(pos2);
+        self.troops[troop2].get().pos = UnitPos::from_index(pos1);
+        self.recalc_army_hitmap();
+        Ok(())
+    }
+    /// Moves the troop from the line to the first reserve cell it fits in
+    pub fn move_to_reserve(&mut self, troop: usize) -> Result<(), ()> {
+        self.move_to_field(troop, true)
+    }
+    /// Moves the troop from the reserve to the line, where the formation prefers
+    pub fn move_from_reserve(&mut self, troop: usize) -> Result<(), ()> {
+        self.move_to_field(troop, false)
+    }
+    fn move_to_field(&mut self, troop: usize, reserve: bool) -> Result<(), ()> {
+        let target = |index: usize| (field_type(index, *MAX_TROOPS) == Field::Reserve) == reserve;
+        let to = {
+            let troop_ref = self.troops.get(troop).ok_or(())?.get();
+            if target(troop_ref.pos.into()) {
+                return Err(());
+            }
+            self.formation_cell(&self.hitmap_without(troop), &troop_ref.unit, target)
+                .ok_or(())?
+        };
+        self.move_troop(troop, to)
+    }
+    /// Promotes the troop to the unit `to` if it is available and fits into the army
+    pub fn promote_troop(&mut self, troop: usize, to: usize, units: &Vec<Unit>) -> Result<(), ()> {
+        let wrap_troop = self.troops.get(troop).ok_or(())?.clone();
+        let mut troop_ref = wrap_troop.get();
+        if !troop_ref.promotions(units).contains(&to) {
+            return Err(());
+        }
+        let size = units[to].info.size;
+        let pos = troop_ref.pos;
+        let pos = if self.fits_instead(troop, size, pos.into()) {
+            pos
+        } else {
+            let hitmap = self.hitmap_without(troop);
+            let possible = Army::fit(&hitmap, size, *MAX_LINES, *MAX_TROOPS / *MAX_LINES);
+            UnitPos::from_index(*possible.first().ok_or(())?)
+        };
+        troop_ref.promote(&units[to]);
+        troop_ref.pos = pos;
+        drop(troop_ref);
+        self.recalc_army_hitmap();
+        Ok(())
+    }
+    /// Promotes every troop that reached the level of its first fitting promotion.
+    /// Returns pairs of troop index and unit it was promoted to
+    pub fn auto_promote(&mut self, units: &Vec<Unit>) -> Vec<(usize, usize)> {
+        let mut promoted = Vec::new();
+        for troop in 0..self.troops.len() {
+            let promotions = self.troops[troop].get().promotions(units);
+            if let Some(to) = promotions
+                .into_iter()
+                .find(|to| self.promote_troop(troop, *to, units).is_ok())
+            {
+                promoted.push((troop, to));
+            }
+        }
+        promoted
+    }
+    /// Best market discount of the alive troops, see `Bonus::trade_discount`
+    pub fn trade_discount(&self) -> Percent {
+        self.troops
+            .iter()
+            .map(|troop| troop.get())
+            .filter(|troop| !troop.is_dead())
+            .map(|troop| troop.unit.get_bonus().trade_discount())
+            .max_by_key(|discount| discount.get())
+            .unwrap_or_default()
+    }
+    /// Regenerates the alive troops, see `Unit::regenerate`
+    pub fn regenerate(&mut self) -> bool {
+        let mut regenerated = false;
+        for troop in &self.troops {
+            regenerated |= troop.get().unit.regenerate();
+        }
+        regenerated
+    }
     pub fn add_item(&mut self, item: Item) {
         self.inventory.push(item)
     }
@@ -235,6 +482,17 @@ impl Army {
             self.inventory.remove(index);
         }
     }
+    /// The troop drinks the item from the inventory, see `Item::drink`
+    pub fn drink_item(&mut self, item: usize, troop: usize, now: Time) -> Result<(), ()> {
+        let (Some(potion), Some(troop)) = (self.inventory.get(item), self.troops.get(troop)) else {
+            return Err(());
+        };
+        if !potion.drink(&mut troop.get().unit, now) {
+            return Err(());
+        }
+        self.inventory.remove(item);
+        Ok(())
+    }
     pub fn get_troop(&self, pos: usize) -> Option<TroopType> {
         if let Some(index) = self.hitmap[pos] {
             return self.troops.get(index).cloned();
@@ -320,3 +578,61 @@ pub fn find_path(
     )?;
     Some((path.0[1..].to_vec(), path.1))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{self, unit};
+
+    fn army_of(names: &[&str], formation: Formation) -> Army {
+        let mut army = test_helpers::army_of(vec![], 0);
+        army.formation = formation;
+        for name in names {
+            army.add_troop(Troop::new(unit(name)).into()).unwrap();
+        }
+        army
+    }
+    fn cells(army: &Army) -> Vec<usize> {
+        army.troops
+            .iter()
+            .map(|troop| troop.get().pos.into())
+            .collect()
+    }
+    #[test]
+    fn moving_troops() {
+        let mut army = army_of(&["Рыцарь", "Требушет"], Formation::FirstFree);
+        // The trebuchet is two cells wide and does not fit into the reserve
+        assert_eq!(cells(&army), vec![0, 1]);
+        assert_eq!(army.hitmap[2], Some(1));
+        assert!(army.move_troop(0, 2).is_err());
+        assert!(army.move_troop(1, 5).is_err());
+        army.move_troop(0, 11).unwrap();
+        assert!(army.swap_troops(0, 1).is_err());
+        army.move_troop(0, 7).unwrap();
+        army.swap_troops(0, 1).unwrap();
+        assert_eq!(cells(&army), vec![1, 7]);
+        assert_eq!((army.hitmap[7], army.hitmap[8]), (Some(1), Some(1)));
+
+        assert!(army.move_from_reserve(0).is_err());
+        assert!(army.move_to_reserve(1).is_err());
+        army.move_to_reserve(0).unwrap();
+        assert_eq!(cells(&army), vec![0, 7]);
+        army.move_from_reserve(0).unwrap();
+        assert_eq!(cells(&army), vec![1, 7]);
+    }
+    #[test]
+    fn formation_presets() {
+        let mut army = army_of(&["Рыцарь", "Лучник", "Рыцарь"], Formation::RangedBack);
+        assert_eq!(cells(&army), vec![6, 1, 7]);
+        army.set_formation(Formation::FrontFirst).unwrap();
+        assert_eq!(cells(&army), vec![6, 7, 8]);
+        army.set_formation(Formation::FirstFree).unwrap();
+        assert_eq!(cells(&army), vec![0, 1, 2]);
+        army.set_formation(Formation::RangedBack).unwrap();
+        // Hired troops follow the saved formation
+        let archer = army.troops[1].get().unit.clone();
+        army.add_troop(Troop::new(archer).into()).unwrap();
+        assert_eq!(cells(&army), vec![6, 1, 7, 2]);
+        assert_eq!(army.formation, Formation::RangedBack);
+    }
+}
@@ -1,16 +1,24 @@
 use crate::{
     battle::{
-        army::{Army, TroopType, MAX_TROOPS},
+        ai::is_ai_controlled,
+        army::{Army, TroopType, MAX_LINES, MAX_TROOPS},
+        event::{unit_ref, BattleEvent, UnitSnapshot},
+        experience::{distribute_experience, ArmyBattleStats, BattleSummary, TroopBattleStats},
+        rules::{BattleRules, BATTLE_RULES, BATTLE_SETTINGS},
+        siege::Siege,
+        terrain::{TerrainInfo, TERRAINS},
         troop::Troop,
     },
     items::item::Item,
     map::map::GameMap,
+    mutrc::SendMut,
     network::net::*,
     units::unit::*,
 };
 use alkahest::{alkahest, serialize, serialized_size};
+use math_thingies::Percent;
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
 use renet::DefaultChannel;
-use std::cmp::Ordering::*;
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum Field {
@@ -18,10 +26,12 @@ pub enum Field {
     Back,
     Reserve,
 }
+/// The last line is the front, reserve cells are set by `BattleSettings::reserve`
 pub fn field_type(index: usize, max_troops: usize) -> Field {
+    let columns = max_troops / *MAX_LINES;
     match index {
-        index if index == 0 || index == max_troops / 2 - 1 => Field::Reserve,
-        index if index < max_troops / 2 => Field::Back,
+        index if BATTLE_SETTINGS.is_reserve(index, max_troops) => Field::Reserve,
+        index if index + columns < max_troops => Field::Back,
         _ => Field::Front,
     }
 }
@@ -32,102 +42,268 @@ pub fn troop_inactive(troop: &Troop) -> bool {
 #[derive(Clone, Default, Debug)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct BattleInfo {
+    /// Leader of the first side
     pub army1: usize,
+    /// Leader of the second side
     pub army2: usize,
-    pub battle_ter: usize,
+    /// Every army in the battle, army1 and army2 first.
+    /// `Action::Cell` addresses armies by their index here
+    pub armies: Vec<usize>,
+    /// Side of each army in `armies`: 0 for the side of army1, 1 for the side of army2
+    pub sides: Vec<usize>,
+    /// Index in `TERRAINS` of the terrain under army1, see `GameMap::battle_terrain`
+    pub battle_ter: Option<usize>,
     pub active_unit: Option<(usize, usize)>,
     pub move_count: u64,
     pub can_interact: Option<Vec<(usize, usize)>>,
     pub winner: Option<usize>,
+    /// Army that retreated from the battle
+    pub fled: Option<usize>,
     pub dead: Vec<TroopType>,
+    /// Surrendered troops of the loser, the winner can take them with `take_prisoner`
+    pub prisoners: Vec<TroopType>,
+    /// Statistics of each army in `armies`, in the same order
+    pub stats: Vec<ArmyBattleStats>,
+    pub summary: Option<BattleSummary>,
+    pub seed: u64,
+    /// Every action applied through `handle_action`, in order
+    pub journal: Vec<Action>,
+    /// Events not taken yet, shared between clones of the battle
+    pub events: SendMut<Vec<BattleEvent>>,
+    /// Set when army1 is the garrison of a building
+    pub siege: Option<Siege>,
+    /// Rules from Settings.ini unless the map has its own, see `start_battle`
+    pub rules: BattleRules,
 }
 impl BattleInfo {
     pub fn new(armys: &mut Vec<Army>, army1: usize, army2: usize) -> Self {
+        Self::new_seeded(armys, army1, army2, thread_rng().gen())
+    }
+    pub fn new_seeded(armys: &mut Vec<Army>, army1: usize, army2: usize, seed: u64) -> Self {
+        Self::with_allies_seeded(armys, [vec![army1], vec![army2]], seed)
+    }
+    /// Battle of two sides, the first army of each side is its leader.
+    /// See `GameMap::battle_sides`
+    pub fn with_allies(armys: &mut Vec<Army>, sides: [Vec<usize>; 2]) -> Self {
+        Self::with_allies_seeded(armys, sides, thread_rng().gen())
+    }
+    pub fn with_allies_seeded(armys: &mut Vec<Army>, sides: [Vec<usize>; 2], seed: u64) -> Self {
+        let mut battle = Self::setup(sides, seed);
+        battle.start(armys);
+        battle
+    }
+    /// Battle that is not started yet, so its rules and terrain can be changed before `start`
+    pub fn setup(sides: [Vec<usize>; 2], seed: u64) -> Self {
+        let (army1, army2) = (sides[0][0], sides[1][0]);
         let mut battle = BattleInfo {
             army1,
             army2,
-            battle_ter: armys[army2].building.unwrap_or(0),
+            armies: sides.iter().map(|side| side[0]).collect(),
+            sides: vec![0, 1],
             winner: None,
+            seed,
+            rules: BATTLE_RULES.clone(),
             ..Default::default()
         };
+        for (side, armies) in sides.iter().enumerate() {
+            for &army in armies.iter().skip(1) {
+                battle.armies.push(army);
+                battle.sides.push(side);
+            }
+        }
+        battle
+    }
+    /// Battle of the attacker (army2) against the garrison (army1) of a building
+    pub fn new_siege(armys: &mut Vec<Army>, attacker: usize, siege: Siege) -> Self {
+        let mut battle = Self::setup_siege(attacker, siege);
         battle.start(armys);
         battle
     }
+    /// Siege that is not started yet, see `BattleInfo::setup`
+    pub fn setup_siege(attacker: usize, siege: Siege) -> Self {
+        BattleInfo {
+            army1: siege.garrison,
+            army2: attacker,
+            armies: vec![siege.garrison, attacker],
+            sides: vec![0, 1],
+            seed: thread_rng().gen(),
+            siege: Some(siege),
+            rules: BATTLE_RULES.clone(),
+            ..Default::default()
+        }
+    }
+    /// Terrain of the battle, its modifier is given to every troop at `start`
+    pub fn terrain(&self) -> Option<TerrainInfo> {
+        TERRAINS.lock().unwrap().get(self.battle_ter?).cloned()
+    }
+    /// Random generator for the current action.
+    /// The same seed and journal always give the same numbers
+    pub fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(
+            self.seed ^ (self.journal.len() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15),
+        )
+    }
+    /// Plays the journal again on copies of the armies this battle started with.
+    /// `armys` must be in the state they were in before `BattleInfo::new`
+    pub fn replay(&self, armys: &Vec<Army>) -> (BattleInfo, Vec<Army>) {
+        let mut armys = armys.iter().map(Army::deep_clone).collect();
+        let mut battle = BattleInfo {
+            army1: self.army1,
+            army2: self.army2,
+            armies: self.armies.clone(),
+            sides: self.sides.clone(),
+            battle_ter: self.battle_ter,
+            seed: self.seed,
+            siege: self.siege.clone(),
+            rules: self.rules.clone(),
+            ..Default::default()
+        };
+        battle.start(&mut armys);
+        for action in &self.journal {
+            handle_action(*action, &mut battle, &mut armys);
+        }
+        (battle, armys)
+    }
+    /// Copy of the battle with its own event buffer, for trying actions out
+    pub fn detached(&self) -> BattleInfo {
+        let mut battle = self.clone();
+        battle.events = Default::default();
+        battle
+    }
+    pub fn emit(&self, event: BattleEvent) {
+        self.events.get().push(event);
+    }
+    /// Takes all events emitted since the last call
+    pub fn take_events(&self) -> Vec<BattleEvent> {
+        std::mem::take(&mut *self.events.get())
+    }
     pub fn start(&mut self, armys: &mut Vec<Army>) {
-        let army1 = &mut armys[self.army1];
-
-        army1.troops.iter_mut().for_each(|troop| {
-            let unit = &mut troop.get().unit;
-            let bonus = unit.get_bonus();
-            bonus.on_battle_start(unit, &self);
-            unit.bonus = bonus;
-            unit.recalc();
-        });
-        let army2 = &mut armys[self.army2];
-        army2.troops.iter_mut().for_each(|troop| {
-            let unit = &mut troop.get().unit;
-            let bonus = unit.get_bonus();
-            bonus.on_battle_start(unit, &self);
-            unit.bonus = bonus;
-            unit.recalc();
-        });
+        let terrain = self.terrain();
+        for &army in &self.armies {
+            armys[army].troops.iter_mut().for_each(|troop| {
+                let troop = &mut troop.get();
+                let pos = troop.pos;
+                let unit = &mut troop.unit;
+                let bonus = unit.get_bonus();
+                if bonus.on_battle_start(unit, &self) {
+                    self.emit(BattleEvent::BonusTriggered {
+                        unit: unit_ref(unit, pos),
+                        bonus,
+                    });
+                }
+                if let Some(terrain) = &terrain {
+                    unit.add_effect(terrain.effect());
+                }
+                unit.bonus = bonus;
+                unit.recalc();
+                troop.battle_stats = TroopBattleStats {
+                    all_moves: troop.unit.modified.moves.max(0) as u64,
+                    ..Default::default()
+                };
+            });
+        }
+        if let Some(siege) = &self.siege {
+            siege.fortify(&mut armys[self.army1], self);
+        }
+        self.stats = self
+            .armies
+            .iter()
+            .map(|&army| ArmyBattleStats::new(&armys[army]))
+            .collect();
+        self.summary = None;
         self.winner = None;
         self.active_unit = self.search_next_active(&*armys);
         self.can_interact = search_interactions(self, &*armys);
     }
     pub fn remove_corpses(&mut self, armys: &mut Vec<Army>) {
-        let army1 = &mut armys[self.army1];
-        remove_corpses(self, &mut army1.troops);
-        army1.recalc_army_hitmap();
-        let army2 = &mut armys[self.army2];
-        remove_corpses(self, &mut army2.troops);
-        army2.recalc_army_hitmap();
+        for army in self.armies.clone() {
+            let army = &mut armys[army];
+            remove_corpses(self, &mut army.troops);
+            army.recalc_army_hitmap();
+        }
+    }
+    /// Leader of the side this army fights against
+    pub fn enemy(&self, army: usize) -> usize {
+        self.leader(1 - self.side(army))
+    }
+    /// Leader of the side
+    pub fn leader(&self, side: usize) -> usize {
+        if side == 0 {
+            self.army1
+        } else {
+            self.army2
+        }
+    }
+    /// Armies fighting on the side, the leader first
+    pub fn side_armies(&self, side: usize) -> impl Iterator<Item = usize> + '_ {
+        self.armies
+            .iter()
+            .zip(&self.sides)
+            .filter(move |(_, army_side)| **army_side == side)
+            .map(|(army, _)| *army)
     }
+    /// Index of the army in `armies`, `stats` and `Action::Cell`
+    pub fn slot(&self, army: usize) -> Option<usize> {
+        self.armies.iter().position(|other| *other == army)
+    }
+    /// Moves the prisoner to the winner army if it fits there
+    pub fn take_prisoner(&mut self, armys: &mut Vec<Army>, prisoner: usize) -> Result<(), ()> {
+        let winner = self.winner.ok_or(())?;
+        if prisoner >= self.prisoners.len() {
+            return Err(());
+        }
+        let troop = self.prisoners[prisoner].clone();
+        let army = troop.get().unit.army;
+        troop.get().unit.army = winner;
+        if armys[winner].add_troop(troop.clone()).is_err() {
+            troop.get().unit.army = army;
+            return Err(());
+        }
+        self.prisoners.remove(prisoner);
+        Ok(())
+    }
+    /// Side of the army: 0 for the side of army1, 1 for the side of army2
+    pub fn side(&self, army: usize) -> usize {
+        self.slot(army)
+            .and_then(|slot| self.sides.get(slot).copied())
+            .unwrap_or((army != self.army1) as usize)
+    }
+    /// Counts HP lost by the troop, `dealer` is the army that hit it, if any
+    pub fn record_hp_loss(&mut self, troop: &mut Troop, hp_before: i64, dealer: Option<usize>) {
+        let lost = hp_before.max(0) - troop.unit.modified.hp.max(0);
+        if lost <= 0 {
+            return;
+        }
+        let lost = lost as u64;
+        troop.battle_stats.lost_hp += lost;
+        let slot = self.slot(troop.unit.army);
+        if let Some(stats) = slot.and_then(|slot| self.stats.get_mut(slot)) {
+            stats.lost_hp += lost;
+        }
+        let Some(dealer) = dealer.filter(|dealer| *dealer != troop.unit.army) else {
+            return;
+        };
+        let slot = self.slot(dealer);
+        if let Some(stats) = slot.and_then(|slot| self.stats.get_mut(slot)) {
+            stats.dealt_hp += lost;
+            stats.turn_dealt_hp += lost;
+        }
+    }
+    /// The fastest active troop of all armies, see `first_to_act`
     pub fn search_next_active(&self, armys: &Vec<Army>) -> Option<(usize, usize)> {
-        let army1 = &armys[self.army1];
-        let army2 = &armys[self.army2];
         if self.winner.is_some() {
             return None;
         }
-
-        fn max_speed(troops: &Vec<TroopType>) -> (usize, &TroopType) {
-            troops
-                .iter()
-                .enumerate()
-                .max_by(|inf1, inf2| {
-                    let (troop1, troop2) = (inf1.1.get(), inf2.1.get());
-                    let tr1_inactive = troop_inactive(&*troop1);
-                    let tr2_inactive = troop_inactive(&*troop2);
-                    let res = if tr1_inactive {
-                        Less
-                    } else if tr2_inactive {
-                        Greater
-                    } else {
-                        troop1.unit.modified.speed.cmp(&troop2.unit.modified.speed)
-                    };
-                    res
-                })
-                .unwrap()
-        }
-        let troops = &army1.troops;
-        let next1 = max_speed(&troops);
-        let troops = &army2.troops;
-        let next2 = max_speed(&troops);
-        return {
-            let (troop1, troop2) = (&next1.1.get(), &next2.1.get());
-
-            let tr1_inactive = troop_inactive(&troop1);
-            let tr2_inactive = troop_inactive(&troop2);
-            if troop1.unit.modified.speed > troop2.unit.modified.speed && !tr1_inactive {
-                Some((troop1.unit.army, next1.0))
-            } else if !tr2_inactive {
-                Some((troop2.unit.army, next2.0))
-            } else {
-                None
-            }
-        };
+        first_to_act(self.armies.iter().map(|&army| (army, &armys[army].troops)))
     }
-    pub fn end(&mut self, armys: &mut Vec<Army>) {
+    /// Ends the battle: gives experience, promotes AI troops, moves goods to the winner
+    /// (or takes the retreat penalty from the army that fled), captures prisoners
+    /// and restores corpses.
+    /// Calling it again returns the same summary.
+    pub fn end(&mut self, armys: &mut Vec<Army>) -> BattleSummary {
+        if let Some(summary) = &self.summary {
+            return summary.clone();
+        }
         fn restore_corpses(armys: &mut Vec<Army>, battle: &mut BattleInfo, _winner: usize) {
             let mut corpses = Vec::new();
             corpses.append(&mut battle.dead);
@@ -141,150 +317,177 @@ impl BattleInfo {
                 troop.get().on_battle_end();
             }
         }
+        /// Goods of the losing side are split between the armies of the winning side,
+        /// the leader gets what can not be split evenly
         fn move_goods(
             armys: &mut Vec<Army>,
             battle: &mut BattleInfo,
             winner: usize,
         ) -> (Vec<Item>, u64, u64) {
-            let loose = match winner {
-                winner if winner == battle.army1 => battle.army2,
-                _ => battle.army1,
-            };
+            let side = battle.side(winner);
+            let winners = battle.side_armies(side).collect::<Vec<_>>();
             let mut items = Vec::new();
-            items.append(&mut armys[loose].inventory);
-            armys[winner].inventory.append(&mut items.clone());
-            let gold = armys[loose].stats.gold;
-            armys[loose].stats.gold = 0;
-            armys[winner].stats.gold += gold;
-            let mana = armys[loose]
-                .troops
-                .iter()
-                .map(|troop| troop.get().unit.info.surrender)
-                .sum::<Option<u64>>()
-                .unwrap_or(0);
-            armys[winner].stats.mana += mana;
-            {
+            let (mut gold, mut mana) = (0, 0);
+            for loose in battle.side_armies(1 - side).collect::<Vec<_>>() {
                 let army = &mut armys[loose];
+                items.append(&mut army.inventory);
+                gold += std::mem::take(&mut army.stats.gold);
+                mana += army
+                    .troops
+                    .iter()
+                    .map(|troop| troop.get().unit.info.surrender)
+                    .sum::<Option<u64>>()
+                    .unwrap_or(0);
                 army.defeated = true;
             }
+            let count = winners.len() as u64;
+            for (i, army) in winners.iter().enumerate() {
+                let army = &mut armys[*army];
+                let first = (i == 0) as u64;
+                army.stats.gold += gold / count + first * (gold % count);
+                army.stats.mana += mana / count + first * (mana % count);
+            }
+            for (i, item) in items.iter().enumerate() {
+                armys[winners[i % winners.len()]]
+                    .inventory
+                    .push(item.clone());
+            }
             (items, gold, mana)
         }
+        /// Army that fled loses part of its gold and items, troops in the reserve row stay behind
+        fn retreat_penalty(
+            armys: &mut Vec<Army>,
+            battle: &mut BattleInfo,
+            winner: usize,
+            fled: usize,
+        ) -> (Vec<Item>, u64) {
+            let loss = RETREAT_LOSS;
+            let gold = loss.calc(armys[fled].stats.gold);
+            armys[fled].stats.gold -= gold;
+            armys[winner].stats.gold += gold;
+            let kept = armys[fled].inventory.len() - loss.calc(armys[fled].inventory.len());
+            let items = armys[fled].inventory.split_off(kept);
+            armys[winner].inventory.extend(items.iter().cloned());
+            let army = &mut armys[fled];
+            let (left, kept): (Vec<_>, Vec<_>) = army.troops.drain(..).partition(|troop| {
+                let troop = troop.get();
+                !troop.is_dead() && field_type(troop.pos.into(), *MAX_TROOPS) == Field::Reserve
+            });
+            army.troops = kept;
+            army.recalc_army_hitmap();
+            army.fled = true;
+            battle.prisoners.extend(left);
+            (items, gold)
+        }
+        fn take_prisoners(armys: &mut Vec<Army>, battle: &mut BattleInfo, loser: usize) {
+            let army = &mut armys[loser];
+            let (surrendered, kept): (Vec<_>, Vec<_>) = army.troops.drain(..).partition(|troop| {
+                let troop = troop.get();
+                !troop.is_dead() && troop.unit.info.surrender.is_some()
+            });
+            army.troops = kept;
+            army.recalc_army_hitmap();
+            battle.prisoners.extend(surrendered);
+        }
+        for stats in &mut self.stats {
+            stats.end_turn();
+        }
+        let mut summary = BattleSummary {
+            winner: self.winner,
+            armies: distribute_experience(self, armys),
+            ..Default::default()
+        };
+        if let Ok(units) = UNITS.read() {
+            for army in &mut summary.armies {
+                if is_ai_controlled(&armys[army.army]) {
+                    army.promoted = armys[army.army].auto_promote(&units);
+                }
+            }
+        }
         if let Some(winner) = self.winner {
-            move_goods(armys, self, winner);
+            if let Some(fled) = self.fled {
+                (summary.items, summary.gold) = retreat_penalty(armys, self, winner, fled);
+            } else {
+                (summary.items, summary.gold, summary.mana) = move_goods(armys, self, winner);
+                let side = self.side(winner);
+                for loser in self.side_armies(1 - side).collect::<Vec<_>>() {
+                    take_prisoners(armys, self, loser);
+                }
+            }
+            summary.fled = self.fled;
+            summary.prisoners = self.prisoners.len();
             restore_corpses(armys, self, winner);
         }
-        /*
-        // такстические стоимости (силы) армий
-        CalkArmyCost(Army[1]); CalkArmyCost(Army[2]);
-        // вычисляем полученный опыт исходя из тактических стоимостей
-        For I:=1 to 2 do If Army[I].FirstMaxUnits>0 then begin
-          // соотношение армий с учетом защиты замков
-          dExp:=Army[3-I].FirstTacticCost/Army[I].FirstTacticCost;
-          If dExp>=1
-            then dExp:=1+(dExp-1)*ExpCorrection/100
-            else dExp:=1-(1-dExp)*ExpCorrection/100;
-          If dExp>4 then dExp:=4; If dExp<0.25 then dExp:=0.25;
-          // тут считаем старый вариант расчета опыта
-          If Army[I].FirstTacticCost>0 then begin
-            NewExp:=(Army[3-I].FirstTacticCost-Army[3-I].TacticCost)*dExp;
-            Army[I].OldNewExpirience:=Round(NewExp*MainExpCorrection/100);
-          end else Army[I].OldNewExpirience:=0;
-          // базовое значение опыта
-          BaseExp:=Army[3-I].FirstTacticCost div 20;
-          // учитываем сложность битвы исходя из потери хитов
-          If Army[I].LostHit>0 then begin
-            dExp:=Army[I].NormalLostHit/Army[I].LostHit;
-            If dExp>3 then dExp:=3; If dExp<0.8 then dExp:=0.8;
-            If Army[I].NormalLostHit=0 then begin
-              dExp:=(Army[I].AllHit-Army[I].LostHit)/Army[I].AllHit;
-              If dExp<0 then dExp:=0;
-              NewExp:=BaseExp*dExp;
-            end else NewExp:=BaseExp+Army[I].NormalLostHit*dExp+Army[I].TurnMaxHit
-          end else NewExp:=BaseExp+Army[I].NormalLostHit*3+Army[I].TurnMaxHit;
-          Army[I].NewExpirience:=Round(NewExp);
-          // распределяем по всем учавствовавшим в бою персонажам в зависимости от их действий в бою
-          If Army[I].MaxUnits>0 then For J:=1 to Army[I].MaxUnits do begin
-            dExp:=0.25*Army[I].NewExpirience/Army[I].FirstMaxUnits;
-            If (Army[I].Units[J].AllMov+Army[I].Units[J].MovFree)>0
-              then dExp:=(4-Army[I].Units[J].Row)*dExp+Army[I].Units[J].Row*dExp*Army[I].Units[J].UseMov/(Army[I].Units[J].AllMov+Army[I].Units[J].MovFree);
-            If dExp<0.5 then dExp:=1;
-            Army[I].Units[J].NewExpirience:=Round(dExp);
-          end;
-        end;
-             */
+        self.summary = Some(summary.clone());
+        summary
     }
 }
 
+/// Cells the active unit can act on. Armies of the same side do not act on each other
 pub fn search_interactions(
     battle: &mut BattleInfo,
     armys: &Vec<Army>,
 ) -> Option<Vec<(usize, usize)>> {
-    let army1 = &armys[battle.army1];
-    let army2 = &armys[battle.army2];
+    let active_unit = battle.active_unit?;
+    let active_army = active_unit.0;
+    let active_troop = &armys[active_army].troops[active_unit.1].get();
+    let active_side = battle.side(active_army);
     let mut can_interact = Vec::new();
-    if let Some(active_unit) = battle.active_unit {
-        let active_army = active_unit.0;
-
-        let (tr1, tr2) = (&army1.troops, &army2.troops);
-        let (troops1, troops2) = (&tr1, &tr2);
-        let active_troops = if active_army == battle.army1 {
-            troops1
-        } else {
-            troops2
-        };
-        fn collect_interactions(
-            active_unit: (usize, usize),
-            active_troops: &Vec<TroopType>,
-            troops: &Vec<TroopType>,
-            army: usize,
-            can_interact: &mut Vec<(usize, usize)>,
-        ) {
-            can_interact.append(
-                &mut troops
-                    .iter()
-                    .enumerate()
-                    .map(|(i, troop)| {
-                        if i == active_unit.1 && active_unit.0 == army {
-                            return None;
-                        }
-                        let troop = &troop.get();
-                        let index = troop.pos.into();
-                        let unit = &troop.unit;
-                        let active_troop = &active_troops[active_unit.1].get();
-                        let active_unit_unit = &active_troop.unit;
-                        if active_unit_unit.can_attack(unit, troop.pos, active_troop.pos) {
-                            Some((0, index))
-                        } else {
-                            None
-                        }
-                    })
-                    .filter_map(|e| e.and_then(|v| Some(v)))
-                    .collect(),
-            );
+    for &army in &battle.armies {
+        if army != active_army && battle.side(army) == active_side {
+            continue;
         }
-        collect_interactions(
-            active_unit,
-            active_troops,
-            troops1,
-            battle.army1,
-            &mut can_interact,
-        );
-        collect_interactions(
-            active_unit,
-            active_troops,
-            troops2,
-            battle.army2,
-            &mut can_interact,
+        can_interact.extend(
+            armys[army]
+                .troops
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| (army, *i) != active_unit)
+                .filter_map(|(_, troop)| {
+                    let (_, cell) = attack_cells(active_troop, &troop.get(), None)?;
+                    Some((army, cell.into()))
+                }),
         );
-        return Some(can_interact);
     }
-    None
+    Some(can_interact)
+}
+/// Cells of the attacker and of the target an attack can go through,
+/// the `preferred` cell of the target is tried first
+pub fn attack_cells(
+    attacker: &Troop,
+    target: &Troop,
+    preferred: Option<usize>,
+) -> Option<(UnitPos, UnitPos)> {
+    let mut cells = target.cells();
+    if let Some(index) = preferred.and_then(|preferred| {
+        cells
+            .iter()
+            .position(|cell| <UnitPos as Into<usize>>::into(*cell) == preferred)
+    }) {
+        let cell = cells.remove(index);
+        cells.insert(0, cell);
+    }
+    let my_cells = attacker.cells();
+    cells.into_iter().find_map(|cell| {
+        my_cells
+            .iter()
+            .find(|my_cell| attacker.unit.can_attack(&target.unit, cell, **my_cell))
+            .map(|my_cell| (*my_cell, cell))
+    })
 }
-const MAX_MOVES: u64 = 25;
+/// Percent of gold and items an army loses when it retreats
+const RETREAT_LOSS: Percent = Percent::const_new(50);
 pub fn remove_corpses(battle: &mut BattleInfo, troops: &mut Vec<TroopType>) {
     let mut i = 0;
     loop {
         if troops[i].get().is_dead() {
+            {
+                let troop = troops[i].get();
+                battle.emit(BattleEvent::Death {
+                    unit: unit_ref(&troop.unit, troop.pos),
+                    name: troop.unit.info.name.clone(),
+                });
+            }
             battle.dead.push(troops.remove(i));
             i -= 1;
         }
@@ -294,10 +497,71 @@ pub fn remove_corpses(battle: &mut BattleInfo, troops: &mut Vec<TroopType>) {
         i += 1;
     }
 }
+/// Troop to act first among the troops of armies in the order of `BattleInfo::armies`.
+/// It is the fastest active troop, on equal speed the one of the later army, then the later troop,
+/// so every peer picks the same one
+fn first_to_act<'a>(
+    armies: impl Iterator<Item = (usize, &'a Vec<TroopType>)>,
+) -> Option<(usize, usize)> {
+    armies
+        .enumerate()
+        .flat_map(|(slot, (army, troops))| {
+            troops
+                .iter()
+                .enumerate()
+                .map(move |(index, troop)| (slot, army, index, troop))
+        })
+        .filter_map(|(slot, army, index, troop)| {
+            let troop = troop.get();
+            (!troop_inactive(&troop))
+                .then_some(((troop.unit.modified.speed, slot, index), (army, index)))
+        })
+        .max_by_key(|(order, _)| *order)
+        .map(|(_, active)| active)
+}
+/// Predicts the next `n` activations as (army, troop), the active unit first.
+/// Every activation spends one move, new rounds restore moves like `next_move` does,
+/// so effects like `MoreMoves` run out on time. The prediction stops at the end of the battle
+/// by timeout or when no troop can act any more
+pub fn turn_order(battle: &BattleInfo, armys: &[Army], n: usize) -> Vec<(usize, usize)> {
+    let mut order = Vec::with_capacity(n);
+    if battle.winner.is_some() {
+        return order;
+    }
+    let mut armies: Vec<(usize, Vec<TroopType>)> = battle
+        .armies
+        .iter()
+        .map(|&army| (army, armys[army].deep_clone().troops))
+        .collect();
+    let mut move_count = battle.move_count;
+    let mut new_round = false;
+    while order.len() < n {
+        let next = first_to_act(armies.iter().map(|(army, troops)| (*army, troops)));
+        let Some((army, troop)) = next else {
+            move_count += 1;
+            if new_round || move_count >= battle.rules.max_moves {
+                break;
+            }
+            for (_, troops) in &mut armies {
+                restore_moves(troops);
+            }
+            new_round = true;
+            continue;
+        };
+        order.push((army, troop));
+        let slot = armies.iter().position(|(other, _)| *other == army).unwrap();
+        let mut troop = armies[slot].1[troop].get();
+        troop.unit.stats.moves -= 1;
+        troop.unit.recalc();
+        new_round = false;
+    }
+    order
+}
 pub fn restore_moves(troops: &mut Vec<TroopType>) {
     for troop in troops {
         let unit = &mut troop.get().unit;
         unit.tick();
+        unit.regenerate();
         unit.stats.moves = unit.modified.max_moves;
         unit.recalc()
     }
@@ -308,12 +572,39 @@ pub fn next_move(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
         battle.end(armys);
         return;
     }
-    let army1 = &mut armys[battle.army1];
-    restore_moves(&mut army1.troops);
-    army1.recalc_army_hitmap();
-    let army2 = &mut armys[battle.army2];
-    restore_moves(&mut army2.troops);
-    army2.recalc_army_hitmap();
+    for army in battle.armies.clone() {
+        let army = &mut armys[army];
+        let before = army
+            .troops
+            .iter()
+            .map(|troop| UnitSnapshot::new(&troop.get().unit))
+            .collect::<Vec<_>>();
+        restore_moves(&mut army.troops);
+        army.recalc_army_hitmap();
+        for (troop, before) in army.troops.iter().zip(before) {
+            let troop = &mut troop.get();
+            let who = unit_ref(&troop.unit, troop.pos);
+            let lost = before.hp.max(0) - troop.unit.modified.hp.max(0);
+            if lost > 0 {
+                battle.emit(BattleEvent::Damage {
+                    from: None,
+                    to: who,
+                    damage: Power::empty(),
+                    hp: lost as u64,
+                });
+            }
+            for event in before.changes(&troop.unit, who, None) {
+                battle.emit(event);
+            }
+            battle.record_hp_loss(troop, before.hp, None);
+            if !troop.is_dead() {
+                troop.battle_stats.all_moves += troop.unit.modified.moves.max(0) as u64;
+            }
+        }
+    }
+    for stats in &mut battle.stats {
+        stats.end_turn();
+    }
     battle.move_count += 1;
     check_win(battle, armys);
 }
@@ -328,60 +619,83 @@ pub fn check_win(battle: &mut BattleInfo, armys: &Vec<Army>) {
             })
     }
 
-    if battle.move_count == MAX_MOVES {
-        battle.winner = Some(battle.army1);
+    if battle.fled.is_some() {
+        return;
+    }
+    let decided = battle.winner.is_some();
+    if battle.move_count == battle.rules.max_moves {
+        battle.winner = Some(battle.leader(battle.rules.timeout_winner));
     }
-    if check_army_win(&armys[battle.army1]) {
+    let side_lost = |side| {
+        battle
+            .side_armies(side)
+            .all(|army| check_army_win(&armys[army]))
+    };
+    let lost = (side_lost(0), side_lost(1));
+    if lost.0 {
         battle.winner = Some(battle.army2);
-    } else if check_army_win(&armys[battle.army2]) {
+    } else if lost.1 {
         battle.winner = Some(battle.army1);
     }
+    if let Some(army) = battle.winner.filter(|_| !decided) {
+        battle.emit(BattleEvent::Winner { army });
+    }
 }
 pub fn check_row_fall(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
     fn check_row(army: &mut Army) -> bool {
         let max_troops = *MAX_TROOPS;
+        let columns = max_troops / *MAX_LINES;
         let lower_row_empty = army
             .hitmap
             .iter()
             .enumerate()
-            .skip(max_troops / 2)
+            .skip(max_troops - columns)
             .all(|(i, hit)| hit.is_none() || field_type(i, max_troops) == Field::Reserve);
         lower_row_empty
     }
-    fn row_fall(army: &mut Army) {
+    /// Moves every troop standing fully in the line behind the front one line forward,
+    /// if its whole footprint fits there
+    fn row_fall(army: &mut Army) -> bool {
         let max_troops = *MAX_TROOPS;
-        let falled = army
-            .hitmap
-            .iter()
-            .enumerate()
-            .take(max_troops / 2)
-            .filter(|(i, hit)| hit.is_some() && field_type(*i, max_troops) != Field::Reserve)
-            .clone();
-        {
-            let troops = &army.troops;
-            for (index, _) in falled {
-                if let Some(mut troop) = troops.get(index).and_then(|t| Some(t.get())) {
-                    troop.pos = UnitPos::from_index(
-                        <UnitPos as Into<usize>>::into(troop.pos) + max_troops / 2,
-                    );
+        let columns = max_troops / *MAX_LINES;
+        let mut moved = false;
+        for index in 0..army.troops.len() {
+            let (pos, size) = {
+                let troop = army.troops[index].get();
+                if troop.is_dead() {
+                    continue;
                 }
+                (troop.pos, troop.unit.info.size)
+            };
+            let cell: usize = pos.into();
+            if pos.1 + size.1 + 1 != *MAX_LINES
+                || field_type(cell, max_troops) == Field::Reserve
+                || !army.can_move_troop(index, cell + columns)
+            {
+                continue;
             }
+            army.troops[index].get().pos = UnitPos::from_index(cell + columns);
+            army.recalc_army_hitmap();
+            moved = true;
         }
-        army.recalc_army_hitmap();
+        moved
     }
 
-    for army in [battle.army1, battle.army2] {
-        let army = &mut armys[army];
-        if check_row(army) {
-            row_fall(army);
+    for army in battle.armies.clone() {
+        if check_row(&mut armys[army]) && row_fall(&mut armys[army]) {
+            battle.emit(BattleEvent::RowFall { army });
         }
     }
 }
 
 /// Action represents possibilities in a battle, Cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum Action {
     Cell(usize, usize),
     Move(usize, usize, usize),
+    /// The army of the active unit leaves the battle, its enemy wins
+    Retreat(usize),
 }
 pub fn move_thing(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
     check_win(battle, &armys);
@@ -393,25 +707,25 @@ pub fn move_thing(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
             battle.active_unit = battle.search_next_active(&armys);
         }
         battle.can_interact = search_interactions(battle, &armys);
-    } else {
-        //state.menu_id = Menu::Start as usize;
+    } else if battle.summary.is_none() {
+        battle.end(armys);
     }
 }
 
+/// The active troop acting on the cell, None if nothing was done.
+/// Otherwise the result of the action and whether the troop has no moves left
 fn unit_interaction(
     battle: &mut BattleInfo,
     armys: &mut Vec<Army>,
     pos: usize,
     army: usize,
-) -> (Option<ActionResult>, bool) {
+) -> Option<(Option<ActionResult>, bool)> {
     if let Some(_) = battle.winner {
-        return (None, false);
+        return None;
     }
     let mut action_result = None;
-    let army = if army == 0 {
-        battle.army1
-    } else {
-        battle.army2
+    let Some(&army) = battle.armies.get(army) else {
+        return None;
     };
 
     let mut unit_inactive = false;
@@ -419,32 +733,32 @@ fn unit_interaction(
     let Some(active_unit) = battle.active_unit else {
         battle.active_unit = battle.search_next_active(armys);
         move_thing(battle, armys);
-        return (None, false);
+        return None;
     };
     let Some(target_index) = armys[army].hitmap[pos] else {
         return if army == active_unit.0 {
-            (
-                handle_action(
-                    Action::Move(active_unit.0, active_unit.1, pos),
-                    battle,
-                    armys,
-                )
-                .and_then(|v| Some(v.0)),
-                true,
-            )
+            let res = apply_action(
+                Action::Move(active_unit.0, active_unit.1, pos),
+                battle,
+                armys,
+            )?;
+            Some((res.map(|v| v.0), true))
         } else {
-            (None, false)
+            None
         };
     };
+    if army != active_unit.0 && battle.side(army) == battle.side(active_unit.0) {
+        return None;
+    }
     if active_unit.0 == army && target_index == active_unit.1 {
         let troop = &mut armys[army].troops[target_index].get();
         if troop_inactive(&troop) {
-            return (None, false);
+            return None;
         }
         let unit = &mut troop.unit;
         unit.stats.moves -= 1;
         unit.recalc();
-        return (None, troop_inactive(&troop));
+        return Some((None, troop_inactive(&troop)));
     } else {
         let target_troops = &armys[army].troops;
         let the_troops = {
@@ -456,41 +770,158 @@ fn unit_interaction(
             )
         };
         let (Some(active_troop), Some(target_troop)) = the_troops else {
-            return (None, unit_inactive);
+            return None;
         };
         let (mut active_troop, mut target_troop) = (active_troop.get(), target_troop.get());
         if troop_inactive(&active_troop) {
-            return (None, true);
+            return Some((None, true));
         }
-        let active_unit_index = active_troop.pos.into();
+        let Some((my_pos, target_pos)) = attack_cells(&active_troop, &target_troop, Some(pos))
+        else {
+            return None;
+        };
+        let (hp1, hp2) = (active_troop.unit.modified.hp, target_troop.unit.modified.hp);
+        let (army1, army2) = (active_troop.unit.army, target_troop.unit.army);
+        let (who1, who2) = (
+            unit_ref(&active_troop.unit, active_troop.pos),
+            unit_ref(&target_troop.unit, target_troop.pos),
+        );
+        let (before1, before2) = (
+            UnitSnapshot::new(&active_troop.unit),
+            UnitSnapshot::new(&target_troop.unit),
+        );
         let unit1 = &mut active_troop.unit;
         let unit2 = &mut target_troop.unit;
         if !unit2.is_dead() {
-            let res = unit1.attack(
-                unit2,
-                UnitPos::from_index(pos),
-                UnitPos::from_index(active_unit_index),
-                &battle,
-            );
+            let res = unit1.attack(unit2, target_pos, my_pos, &battle);
+            for event in before2
+                .changes(unit2, who2, Some(who1))
+                .into_iter()
+                .chain(before1.changes(unit1, who1, None))
+            {
+                battle.emit(event);
+            }
             if res.is_some() {
+                let active = active_unit.1;
+                hit_extra_targets(
+                    battle,
+                    armys,
+                    army,
+                    target_index,
+                    active,
+                    unit1,
+                    my_pos,
+                    target_pos,
+                );
                 unit1.stats.moves -= 1;
                 unit1.recalc();
                 if unit1.is_dead() || unit1.modified.moves < 1 {
                     unit_inactive = true;
                 }
+                active_troop.battle_stats.used_moves += 1;
+                battle.record_hp_loss(&mut target_troop, hp2, Some(army1));
+                battle.record_hp_loss(&mut active_troop, hp1, Some(army2));
             }
             action_result = res;
         }
     };
-    (action_result, unit_inactive)
+    action_result.map(|result| (Some(result), unit_inactive))
+}
+
+/// Hits the troops next to the target with the same attack, see `Bonus::extra_targets`.
+/// The attacker (troop `active` of its army) and the target are locked by the caller, so they are skipped
+fn hit_extra_targets(
+    battle: &mut BattleInfo,
+    armys: &Vec<Army>,
+    army: usize,
+    target: usize,
+    active: usize,
+    attacker: &mut Unit,
+    my_pos: UnitPos,
+    target_pos: UnitPos,
+) {
+    let who = unit_ref(attacker, my_pos);
+    let mut hit = vec![target];
+    if army == attacker.army {
+        hit.push(active);
+    }
+    for cell in attacker.get_bonus().extra_targets(target_pos) {
+        let Some(Some(index)) = armys[army].hitmap.get(cell.into()).copied() else {
+            continue;
+        };
+        if hit.contains(&index) {
+            continue;
+        }
+        hit.push(index);
+        let mut troop = armys[army].troops[index].get();
+        if troop.is_dead() {
+            continue;
+        }
+        let hp = troop.unit.modified.hp;
+        let (before, whom) = (
+            UnitSnapshot::new(&troop.unit),
+            unit_ref(&troop.unit, troop.pos),
+        );
+        if attacker
+            .attack(&mut troop.unit, cell, my_pos, battle)
+            .is_none()
+        {
+            continue;
+        }
+        for event in before.changes(&troop.unit, whom, Some(who)) {
+            battle.emit(event);
+        }
+        battle.record_hp_loss(&mut troop, hp, Some(attacker.army));
+    }
+}
+
+/// What the active unit would do to the troop on the given cell, see `Unit::preview_attack`.
+/// Units are copied out of their locks first, so nothing stays locked
+pub fn preview_interaction(
+    battle: &BattleInfo,
+    armys: &Vec<Army>,
+    army: usize,
+    pos: usize,
+) -> Option<AttackPreview> {
+    let active_unit = battle.active_unit?;
+    let target_index = (*armys.get(army)?.hitmap.get(pos)?)?;
+    if (army, target_index) == active_unit {
+        return None;
+    }
+    let me = armys[active_unit.0]
+        .troops
+        .get(active_unit.1)?
+        .get()
+        .clone();
+    let target = armys[army].troops.get(target_index)?.get().clone();
+    if target.is_dead() {
+        return None;
+    }
+    let (my_pos, target_pos) = attack_cells(&me, &target, Some(pos))?;
+    me.unit
+        .preview_attack(&target.unit, target_pos, my_pos, battle)
 }
 
 /// A method that processes an action with the given battle and gamemap, action is done by currently active unit, it will return None if action is impossible due to game rules.
+/// Everything the action caused is emitted as `BattleEvent`s, see `BattleInfo::take_events`.
+/// Only the actions that were applied are written to `BattleInfo::journal`.
 pub fn handle_action(
     action: Action,
     battle: &mut BattleInfo,
     armies: &mut Vec<Army>,
 ) -> Option<(ActionResult, (usize, usize))> {
+    let applied = apply_action(action, battle, armies)?;
+    battle.journal.push(action);
+    applied
+}
+
+/// Does the action without journaling it, None if it was not applied.
+/// Applied actions may have no result, e.g. a troop skipping its move
+fn apply_action(
+    action: Action,
+    battle: &mut BattleInfo,
+    armies: &mut Vec<Army>,
+) -> Option<Option<(ActionResult, (usize, usize))>> {
     match action {
         Action::Cell(pos, army) => {
             if let Some(_) = battle.winner {
@@ -499,17 +930,22 @@ pub fn handle_action(
             }
             let active = battle.active_unit;
             let res = unit_interaction(battle, armies, pos, army);
-            if res.1 {
+            if let Some((_, true)) = res {
                 battle.active_unit = battle.search_next_active(&armies);
             }
             move_thing(battle, armies);
-            res.0.and_then(|v| Some((v, active.unwrap())))
+            let (result, _) = res?;
+            Some(result.zip(active))
         }
         Action::Move(army, troop, to) => {
+            if !armies.get(army)?.can_move_troop(troop, to) {
+                return None;
+            }
             let army = &mut armies[army];
             let unit_inactive = {
                 let troop = &mut army.troops[troop].get();
                 troop.pos = UnitPos::from_index(to);
+                troop.battle_stats.used_moves += 1;
                 let unit = &mut troop.unit;
                 unit.stats.moves -= 1;
                 unit.recalc();
@@ -521,11 +957,46 @@ pub fn handle_action(
                 battle.active_unit = battle.search_next_active(&armies);
             }
             move_thing(battle, armies);
-            Some((ActionResult::Move, active.unwrap()))
+            Some(Some((ActionResult::Move, active.unwrap())))
+        }
+        Action::Retreat(army) => {
+            let active = battle.active_unit?;
+            if battle.winner.is_some() || active.0 != army {
+                return None;
+            }
+            let winner = battle.enemy(army);
+            battle.fled = Some(army);
+            battle.winner = Some(winner);
+            battle.emit(BattleEvent::Winner { army: winner });
+            move_thing(battle, armies);
+            Some(Some((ActionResult::Retreat, active)))
         }
     }
 }
 
+/// Starts a battle between two armies under the rules of the map
+/// on the terrain under army1, their allies standing nearby join it
+pub fn start_battle(gamemap: &mut GameMap, army1: usize, army2: usize) -> BattleInfo {
+    let sides = gamemap.battle_sides(army1, army2);
+    let mut battle = BattleInfo::setup(sides, thread_rng().gen());
+    battle.rules = gamemap.battle_rules.clone();
+    battle.battle_ter = gamemap.battle_terrain(gamemap.armys[army1].pos);
+    battle.start(&mut gamemap.armys);
+    battle
+}
+
+/// Retreats the army from the battle and moves it to a free tile next to it,
+/// returns the new position or None if it can't retreat
+pub fn retreat(
+    battle: &mut BattleInfo,
+    gamemap: &mut GameMap,
+    army: usize,
+) -> Option<(usize, usize)> {
+    gamemap.retreat_tile(army)?;
+    handle_action(Action::Retreat(army), battle, &mut gamemap.armys)?;
+    gamemap.retreat_army(army)
+}
+
 /// Used for processing an action in a context of using a server
 pub fn handle_server_action(connection: &mut Option<ConnectionManager>, action: (usize, usize)) {
     let Some(connection) = connection else {
@@ -573,8 +1044,9 @@ pub fn handle_server_action(connection: &mut Option<ConnectionManager>, action:
 #[cfg(test)]
 mod tests {
     use crate::{
-        battle::ArmyStats,
-        parse::{parse_items, parse_units},
+        battle::{terrain::terrain_of, ArmyStats},
+        map::object::BuildingVariant,
+        parse::{parse_items, parse_terrains, parse_units},
         units::unitstats::ModifyUnitStats,
     };
     use rand::{seq::IteratorRandom, thread_rng, Rng};
@@ -605,6 +1077,7 @@ mod tests {
                 size: (1, 1),
                 unit_type: UnitType::People,
                 next_unit: Vec::new(),
+                next_unit_lvl: Vec::new(),
                 magic_type: None,
                 surrender: None,
                 lvl: LevelUpInfo::empty(),
@@ -700,6 +1173,251 @@ mod tests {
         }
     }
     #[test]
+    fn predicting_turn_order() {
+        let army_of = |army_num: usize, speeds: &[i64]| {
+            let mut army = gen_army(army_num);
+            army.troops.clear();
+            army.recalc_army_hitmap();
+            for &speed in speeds {
+                army.add_troop(Troop::new(get_unit(1, speed, army_num)).into())
+                    .unwrap();
+            }
+            army
+        };
+        let mut armys = vec![army_of(0, &[5, 3]), army_of(1, &[5, 4])];
+        let mut battle = BattleInfo::new(&mut armys, 0, 1);
+        armys[0].troops[1]
+            .get()
+            .unit
+            .add_effect(crate::effects::effect::MoreMoves::default());
+        // Equal speed goes to the later army, the extra move lasts one round
+        let round1 = vec![(1, 0), (0, 0), (1, 1), (0, 1), (0, 1)];
+        let round2 = vec![(1, 0), (0, 0), (1, 1), (0, 1)];
+        let expected = [round1.clone(), round2.clone(), round2].concat();
+        assert_eq!(turn_order(&battle, &armys, 13), expected);
+        assert_eq!(turn_order(&battle, &armys, 2), expected[..2]);
+        battle.rules.max_moves = battle.move_count + 1;
+        assert_eq!(turn_order(&battle, &armys, 13), round1);
+        battle.rules.max_moves = 25;
+
+        let mut played = vec![];
+        while played.len() < expected.len() {
+            let Some(active) = battle.search_next_active(&armys) else {
+                next_move(&mut battle, &mut armys);
+                continue;
+            };
+            played.push(active);
+            let troop = &mut armys[active.0].troops[active.1].get();
+            troop.unit.stats.moves -= 1;
+            troop.unit.recalc();
+        }
+        assert_eq!(played, expected);
+    }
+    #[test]
+    fn retreat_battle() {
+        let mut armys = vec![gen_army(0), gen_army(1)];
+        armys[0].stats.gold = 100;
+        armys[1].stats.gold = 100;
+        let mut battle = BattleInfo::new(&mut armys, 0, 1);
+        let army = battle.active_unit.unwrap().0;
+        let enemy = battle.enemy(army);
+        assert!(handle_action(Action::Retreat(enemy), &mut battle, &mut armys).is_none());
+        assert!(handle_action(Action::Retreat(army), &mut battle, &mut armys).is_some());
+        assert_eq!(battle.winner, Some(enemy));
+        assert_eq!(battle.summary.as_ref().unwrap().fled, Some(army));
+        assert!(armys[army].fled && !armys[army].defeated);
+        assert_eq!(armys[army].stats.gold, 50);
+        assert_eq!(armys[enemy].stats.gold, 150);
+        // Troops in both reserve cells stay behind
+        assert_eq!(battle.prisoners.len(), 2);
+        assert_eq!(armys[army].troops.len(), 8);
+        battle.take_prisoner(&mut armys, 0).unwrap();
+        assert_eq!(battle.prisoners.len(), 1);
+        assert_eq!(armys[enemy].troops.len(), 11);
+        assert_eq!(armys[enemy].troops[10].get().unit.army, enemy);
+    }
+    fn place(army: &mut Army, unit: Unit, size: (usize, usize), index: usize) {
+        let mut troop = Troop::new(unit);
+        troop.unit.info.size = size;
+        troop.pos = UnitPos::from_index(index);
+        army.troops.push(troop.into());
+        army.recalc_army_hitmap();
+    }
+    #[test]
+    fn mixed_size_battle() {
+        let mut armys = vec![gen_army(0), gen_army(1)];
+        armys[0].troops.clear();
+        armys[1].troops.clear();
+        // Giant on cells 7 and 8, tall unit on cells 3 and 9
+        place(&mut armys[0], get_unit(1, 1, 0), (2, 1), 7);
+        place(&mut armys[0], get_unit(1, 1, 0), (1, 2), 3);
+        let mut fighter = get_unit(1, 10, 1);
+        fighter.stats.damage.hand = 10;
+        fighter.recalc();
+        place(&mut armys[1], fighter, (1, 1), 8);
+        assert_eq!((armys[0].hitmap[7], armys[0].hitmap[8]), (Some(0), Some(0)));
+        assert_eq!((armys[0].hitmap[3], armys[0].hitmap[9]), (Some(1), Some(1)));
+
+        let mut battle = BattleInfo::new(&mut armys, 0, 1);
+        assert_eq!(battle.active_unit, Some((1, 0)));
+        // Every troop once, through a cell the fighter can reach
+        assert_eq!(battle.can_interact, Some(vec![(0, 7), (0, 9)]));
+        assert!(preview_interaction(&battle, &armys, 0, 3).is_some());
+        assert!(handle_action(Action::Cell(8, 0), &mut battle, &mut armys).is_some());
+        assert!(armys[0].troops[0].get().unit.modified.hp < 100);
+
+        // The giant blocks both cells and moves only where it fits
+        assert!(armys[0].can_move_troop(0, 10));
+        assert!(!armys[0].can_move_troop(0, 11));
+        assert!(!armys[0].can_move_troop(0, 8));
+        assert!(!armys[0].can_move_troop(1, 8));
+        let giant_moves = armys[0].troops[0].get().unit.stats.moves;
+        assert!(handle_action(Action::Move(0, 0, 11), &mut battle, &mut armys).is_none());
+        assert_eq!(armys[0].troops[0].get().unit.stats.moves, giant_moves);
+        assert_eq!(armys[0].troops[0].get().pos, UnitPos(1, 1));
+    }
+    #[test]
+    fn mixed_size_row_fall() {
+        let mut armys = vec![gen_army(0), gen_army(1)];
+        armys[0].troops.clear();
+        place(&mut armys[0], get_unit(1, 1, 0), (2, 1), 1);
+        place(&mut armys[0], get_unit(1, 1, 0), (1, 1), 4);
+        let mut battle = BattleInfo::new(&mut armys, 0, 1);
+        battle.take_events();
+        check_row_fall(&mut battle, &mut armys);
+        assert_eq!(armys[0].troops[0].get().pos, UnitPos(1, 1));
+        assert_eq!(armys[0].troops[1].get().pos, UnitPos(4, 1));
+        assert_eq!(
+            armys[0].hitmap[6..],
+            [None, Some(0), Some(0), None, Some(1), None]
+        );
+        assert!(matches!(
+            battle.take_events()[..],
+            [BattleEvent::RowFall { army: 0 }]
+        ));
+    }
+    #[test]
+    fn battle_terrain() {
+        assert!(parse_terrains(Some("dt/Terrains.ini")).is_empty());
+        let swamp = terrain_of(8, None).unwrap();
+        let castle = terrain_of(8, Some(&BuildingVariant::Castle)).unwrap();
+        assert_eq!(TERRAINS.lock().unwrap()[swamp].name, "Болото");
+        assert_ne!(swamp, castle);
+        assert_eq!(terrain_of(6, Some(&BuildingVariant::Mine)), None);
+
+        let mut armys = vec![gen_army(0), gen_army(1)];
+        let mut battle = BattleInfo::setup([vec![0], vec![1]], 0);
+        battle.battle_ter = Some(castle);
+        battle.start(&mut armys);
+        assert_eq!(battle.terrain().unwrap().name, "Крепость");
+        for troop in armys.iter().flat_map(|army| &army.troops) {
+            let defence = troop.get().unit.modified.defence;
+            assert_eq!((defence.hand_units, defence.ranged_units), (5, 5));
+        }
+    }
+    #[test]
+    fn timeout_winner() {
+        let mut armys = vec![gen_army(0), gen_army(1)];
+        let mut battle = BattleInfo::new(&mut armys, 0, 1);
+        battle.rules.max_moves = 2;
+        battle.rules.timeout_winner = 1;
+        // Units without damage only skip their moves
+        while let Some(active_unit) = battle.active_unit {
+            let pos = armys[active_unit.0].troops[active_unit.1].get().pos;
+            let slot = battle.slot(active_unit.0).unwrap();
+            handle_action(Action::Cell(pos.into(), slot), &mut battle, &mut armys);
+        }
+        assert_eq!(battle.move_count, 2);
+        assert_eq!(battle.winner, Some(1));
+    }
+    #[test]
+    fn siege_battle() {
+        let mut armys = vec![gen_army(0), gen_army(1)];
+        let siege = Siege {
+            building: 0,
+            garrison: 1,
+            additional_defense: 5,
+            left_out: Vec::new(),
+            resolved: false,
+        };
+        let battle = BattleInfo::new_siege(&mut armys, 0, siege);
+        assert_eq!((battle.army1, battle.army2), (1, 0));
+        let garrison_bonuses = battle
+            .take_events()
+            .into_iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    BattleEvent::BonusTriggered {
+                        unit: (1, _),
+                        bonus: crate::bonuses::Bonus::Garrison
+                    }
+                )
+            })
+            .count();
+        assert_eq!(garrison_bonuses, armys[1].troops.len());
+        for troop in &armys[1].troops {
+            let unit = &troop.get().unit;
+            assert_eq!(unit.effects.len(), 2);
+            assert_eq!(unit.modified.defence.hand_units, 10);
+        }
+        for troop in &armys[0].troops {
+            assert!(troop.get().unit.effects.is_empty());
+        }
+    }
+    #[test]
+    fn allied_battle() {
+        let res = parse_units(Some("dt/Units.ini"));
+        let Ok((units, _)) = res else {
+            panic!("Unit parsing error")
+        };
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let mut gamemap = GameMap::default();
+        gamemap.armys = (0..4).map(|i| gen_army_from_units(i, &units)).collect();
+        gamemap.armys[1].group = crate::battle::control::PLAYER_GROUP;
+        gamemap.armys[1].pos = (1, 1);
+        // The player does not like neighbours enough to fight with them
+        gamemap.armys[2].group = crate::battle::control::NEIGHBOUR_GROUP;
+        gamemap.armys[2].pos = (0, 1);
+        // Allies stand next to the player only
+        gamemap.armys[3].group = crate::battle::control::ALLY_GROUP;
+        gamemap.armys[3].pos = (2, 2);
+        assert_eq!(gamemap.battle_sides(0, 1), [vec![0], vec![1, 3]]);
+        for _ in 0..100 {
+            let mut armys = (0..4)
+                .map(|i| gen_army_from_units(i, &units))
+                .collect::<Vec<_>>();
+            armys[0].stats.gold = 100;
+            armys[1].stats.gold = 101;
+            let mut battle = BattleInfo::with_allies(&mut armys, [vec![0, 2], vec![1, 3]]);
+            assert_eq!(battle.armies, vec![0, 1, 2, 3]);
+            assert_eq!(battle.enemy(2), 1);
+            while battle.winner.is_none() {
+                let active_side = battle.active_unit.map(|unit| battle.side(unit.0));
+                for &(army, _) in battle.can_interact.iter().flatten() {
+                    let allied = Some(battle.side(army)) == active_side;
+                    assert!(!allied || Some(army) == battle.active_unit.map(|unit| unit.0));
+                }
+                if let Some(action) = crate::battle::ai::choose_action(&battle, &armys) {
+                    handle_action(action, &mut battle, &mut armys);
+                }
+                move_thing(&mut battle, &mut armys);
+            }
+            let summary = battle.end(&mut armys);
+            assert_eq!(summary.armies.len(), 4);
+            let winner = battle.winner.unwrap();
+            let side = battle.side(winner);
+            let ally = battle.side_armies(side).nth(1).unwrap();
+            // The loot is split in two, the leader also gets the odd coin
+            assert_eq!(summary.gold, [101, 100][side]);
+            assert_eq!(armys[winner].stats.gold, 151);
+            assert_eq!(armys[ally].stats.gold, 50);
+            for loser in battle.side_armies(1 - side) {
+                assert!(armys[loser].defeated);
+            }
+        }
+    }
+    #[test]
     fn process_battles() {
         let res = parse_units(Some("dt/Units.ini"));
         let Ok((units, _)) = res else {
@@ -711,10 +1429,12 @@ mod tests {
             let army2 = gen_army_from_units(1, &units);
             let mut armys = vec![army1, army2];
             let mut battle = BattleInfo::new(&mut armys, 0, 1);
+            let mut rng = battle.rng();
             while battle.winner.is_none() {
                 if let Some(interactions) = &battle.can_interact.clone() {
-                    if let Some(interaction) = interactions.iter().choose(&mut thread_rng()) {
-                        unit_interaction(&mut battle, &mut armys, interaction.0, interaction.1);
+                    if let Some(interaction) = interactions.iter().choose(&mut rng) {
+                        let slot = battle.slot(interaction.0).unwrap();
+                        unit_interaction(&mut battle, &mut armys, interaction.1, slot);
                     }
                 }
                 move_thing(&mut battle, &mut armys);
@@ -722,4 +1442,206 @@ mod tests {
             battle.end(&mut armys);
         }
     }
+    #[test]
+    fn battle_events() {
+        let res = parse_units(Some("dt/Units.ini"));
+        let Ok((units, _)) = res else {
+            panic!("Unit parsing error")
+        };
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        for _ in 0..1000 {
+            let army1 = gen_army_from_units(0, &units);
+            let army2 = gen_army_from_units(1, &units);
+            let mut armys = vec![army1, army2];
+            let troops = armys
+                .iter()
+                .flat_map(|army| army.troops.iter().cloned())
+                .collect::<Vec<_>>();
+            let mut battle = BattleInfo::new(&mut armys, 0, 1);
+            let mut events = battle.take_events();
+            let mut rng = battle.rng();
+            while battle.winner.is_none() {
+                let target = battle
+                    .can_interact
+                    .as_ref()
+                    .and_then(|interactions| interactions.iter().choose(&mut rng).copied());
+                match (target, battle.active_unit) {
+                    (Some(target), _) => {
+                        handle_action(
+                            Action::Cell(target.1, battle.slot(target.0).unwrap()),
+                            &mut battle,
+                            &mut armys,
+                        );
+                    }
+                    (None, Some(active_unit)) => {
+                        let pos = armys[active_unit.0].troops[active_unit.1].get().pos;
+                        handle_action(
+                            Action::Cell(pos.into(), battle.slot(active_unit.0).unwrap()),
+                            &mut battle,
+                            &mut armys,
+                        );
+                    }
+                    (None, None) => move_thing(&mut battle, &mut armys),
+                }
+                events.append(&mut battle.take_events());
+            }
+            let deaths = events
+                .iter()
+                .filter(|event| matches!(event, BattleEvent::Death { .. }))
+                .count();
+            let dead = troops.iter().filter(|troop| troop.get().is_dead()).count();
+            assert_eq!(deaths, dead);
+            let winners = events
+                .iter()
+                .filter_map(|event| match event {
+                    BattleEvent::Winner { army } => Some(*army),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(winners, battle.winner.into_iter().collect::<Vec<_>>());
+        }
+    }
+    #[test]
+    fn replay_battles() {
+        let res = parse_units(Some("dt/Units.ini"));
+        let Ok((units, _)) = res else {
+            panic!("Unit parsing error")
+        };
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        fn hp_total(armys: &Vec<Army>) -> i64 {
+            armys
+                .iter()
+                .flat_map(|army| army.troops.iter())
+                .map(|troop| troop.get().unit.stats.hp)
+                .sum()
+        }
+        for _ in 0..1000 {
+            let start = vec![
+                gen_army_from_units(0, &units),
+                gen_army_from_units(1, &units),
+            ];
+            let mut armys = start.iter().map(Army::deep_clone).collect::<Vec<_>>();
+            let mut battle = BattleInfo::new(&mut armys, 0, 1);
+            let invalid = Action::Move(0, usize::MAX, 0);
+            assert!(handle_action(invalid, &mut battle, &mut armys).is_none());
+            assert!(battle.journal.is_empty());
+            let mut rng = battle.rng();
+            while battle.winner.is_none() {
+                let Some(active_unit) = battle.active_unit else {
+                    move_thing(&mut battle, &mut armys);
+                    continue;
+                };
+                let target = battle
+                    .can_interact
+                    .as_ref()
+                    .and_then(|interactions| interactions.iter().choose(&mut rng).copied())
+                    .unwrap_or_else(|| {
+                        let pos = armys[active_unit.0].troops[active_unit.1].get().pos;
+                        (active_unit.0, pos.into())
+                    });
+                let action = Action::Cell(target.1, battle.slot(target.0).unwrap());
+                handle_action(action, &mut battle, &mut armys);
+            }
+            let (replayed, replayed_armys) = battle.replay(&start);
+            assert_eq!(replayed.winner, battle.winner);
+            assert_eq!(replayed.move_count, battle.move_count);
+            assert_eq!(replayed.journal, battle.journal);
+            assert_eq!(replayed.seed, battle.seed);
+            assert_eq!(hp_total(&replayed_armys), hp_total(&armys));
+        }
+    }
+    #[test]
+    fn ai_battles() {
+        let res = parse_units(Some("dt/Units.ini"));
+        let Ok((units, _)) = res else {
+            panic!("Unit parsing error")
+        };
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        for _ in 0..100 {
+            let army1 = gen_army_from_units(0, &units);
+            let army2 = gen_army_from_units(1, &units);
+            let mut armys = vec![army1, army2];
+            let mut battle = BattleInfo::new(&mut armys, 0, 1);
+            while battle.winner.is_none() {
+                if let Some(action) = crate::battle::ai::choose_action(&battle, &armys) {
+                    handle_action(action, &mut battle, &mut armys);
+                }
+                move_thing(&mut battle, &mut armys);
+            }
+            assert!(battle.move_count <= battle.rules.max_moves);
+            let summary = battle.end(&mut armys);
+            for army in &summary.armies {
+                for troop in &army.troops {
+                    assert!(troop.xp > 0);
+                    let unit = &armys[army.army].troops[troop.index].get().unit;
+                    let spent = troop.level_up.as_ref().map_or(0, |report| report.xp_spent);
+                    assert_eq!(unit.lvl.xp + spent, troop.xp);
+                }
+            }
+        }
+    }
+    #[test]
+    fn power_ratio_experience() {
+        use crate::battle::experience::{
+            corrected_experience, old_army_experience, power_ratio, ArmyBattleStats,
+        };
+        let weak = ArmyBattleStats {
+            first_tactic_cost: 100,
+            first_units: 1,
+            ..Default::default()
+        };
+        let strong = ArmyBattleStats {
+            first_tactic_cost: 300,
+            ..weak
+        };
+        let full = Percent::new(100);
+        assert_eq!(power_ratio(&weak, &strong, full), 3.);
+        assert_eq!(power_ratio(&weak, &strong, Percent::new(50)), 2.);
+        assert_eq!(power_ratio(&weak, &strong, Percent::new(0)), 1.);
+        assert!((power_ratio(&strong, &weak, full) - 1. / 3.).abs() < 1e-6);
+        let huge = ArmyBattleStats {
+            first_tactic_cost: 1000,
+            ..weak
+        };
+        assert_eq!(power_ratio(&weak, &huge, full), 4.);
+        assert_eq!(power_ratio(&huge, &weak, full), 0.25);
+        // Beating the strong army gives three times the power destroyed
+        assert_eq!(old_army_experience(&weak, &strong, 100, 3.), 600);
+        assert_eq!(old_army_experience(&strong, &weak, 0, 1. / 3.), 33);
+        // Armies without power do not divide by zero
+        let empty = ArmyBattleStats::default();
+        assert_eq!(power_ratio(&empty, &empty, full), 1.);
+        assert_eq!(power_ratio(&empty, &weak, full), 4.);
+        assert_eq!(power_ratio(&weak, &empty, full), 0.25);
+        // The correction scales the experience troops get
+        assert_eq!(corrected_experience(200, full), 200);
+        assert_eq!(corrected_experience(200, Percent::new(150)), 300);
+        assert_eq!(corrected_experience(200, Percent::new(-50)), 0);
+    }
+    #[test]
+    fn auto_resolved_battles() {
+        let res = parse_units(Some("dt/Units.ini"));
+        let Ok((units, _)) = res else {
+            panic!("Unit parsing error")
+        };
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        for _ in 0..20 {
+            let mut armys = vec![
+                gen_army_from_units(0, &units),
+                gen_army_from_units(1, &units),
+            ];
+            // Quick combat plays for the player too
+            armys[1].control = crate::battle::control::Control::Player(1);
+            let troops: Vec<_> = armys.iter().map(|army| army.troops.len()).collect();
+            let mut battle = BattleInfo::new(&mut armys, 0, 1);
+            let summary = crate::battle::ai::auto_resolve(&mut battle, &mut armys);
+            assert!(battle.winner.is_some());
+            assert_eq!(summary.winner, battle.winner);
+            assert!(battle.move_count <= battle.rules.max_moves);
+            for army in &summary.armies {
+                assert!(army.casualties + army.troops.len() <= troops[army.army]);
+            }
+            assert_eq!(battle.end(&mut armys).winner, summary.winner);
+        }
+    }
 }
@@ -3,6 +3,14 @@ use alkahest::*;
 use math_thingies::Percent;
 
 use crate::time::time::Time;
+/// Groups of armies and buildings, in the order of `Relations`
+pub const PLAYER_GROUP: usize = 0;
+pub const ALLY_GROUP: usize = 1;
+pub const NEIGHBOUR_GROUP: usize = 2;
+pub const ENEMY_GROUP: usize = 3;
+/// Armies fight on the same side only if both attitudes are below this
+pub const ALLIED_ATTITUDE: u8 = 128;
+
 #[derive(Clone, Debug)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Relations {
@@ -33,6 +41,25 @@ impl Ini for Relations {
         (self.player, self.ally, self.neighbour, self.enemy).vomit()
     }
 }
+impl Relations {
+    /// Attitude towards the group, 0 is the friendliest
+    pub fn towards(&self, group: usize) -> u8 {
+        match group {
+            PLAYER_GROUP => self.player,
+            ALLY_GROUP => self.ally,
+            NEIGHBOUR_GROUP => self.neighbour,
+            _ => self.enemy,
+        }
+    }
+    pub fn towards_mut(&mut self, group: usize) -> &mut u8 {
+        match group {
+            PLAYER_GROUP => &mut self.player,
+            ALLY_GROUP => &mut self.ally,
+            NEIGHBOUR_GROUP => &mut self.neighbour,
+            _ => &mut self.enemy,
+        }
+    }
+}
 impl Default for Relations {
     fn default() -> Self {
         Self {
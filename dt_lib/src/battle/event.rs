@@ -0,0 +1,101 @@
+use crate::{
+    bonuses::Bonus,
+    effects::effect::{EffectKind, EffectTrait},
+    units::unit::{Power, Unit, UnitPos},
+};
+use alkahest::alkahest;
+
+/// Unit on the battlefield as army index and cell index, like in `BattleInfo::can_interact`
+pub type UnitRef = (usize, usize);
+
+pub fn unit_ref(unit: &Unit, pos: UnitPos) -> UnitRef {
+    (unit.army, pos.into())
+}
+
+/// Something that happened in a battle, for battle logs, animations and broadcasts
+#[derive(Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub enum BattleEvent {
+    /// `damage` is split as it was after defence and bonuses, `hp` is what the unit really lost.
+    /// Damage from effects such as poison has no dealer and no split
+    Damage {
+        from: Option<UnitRef>,
+        to: UnitRef,
+        damage: Power,
+        hp: u64,
+    },
+    Heal {
+        from: Option<UnitRef>,
+        to: UnitRef,
+        hp: u64,
+    },
+    EffectAdded {
+        unit: UnitRef,
+        kind: EffectKind,
+    },
+    EffectExpired {
+        unit: UnitRef,
+        kind: EffectKind,
+    },
+    BonusTriggered {
+        unit: UnitRef,
+        bonus: Bonus,
+    },
+    /// The front row of the army was empty and its back row moved forward
+    RowFall {
+        army: usize,
+    },
+    Death {
+        unit: UnitRef,
+        name: String,
+    },
+    Winner {
+        army: usize,
+    },
+}
+
+/// HP and effects of a unit before an action, to find out what the action changed
+pub struct UnitSnapshot {
+    pub hp: i64,
+    pub effects: Vec<EffectKind>,
+}
+impl UnitSnapshot {
+    pub fn new(unit: &Unit) -> Self {
+        Self {
+            hp: unit.modified.hp,
+            effects: unit
+                .effects
+                .iter()
+                .map(|effect| effect.get_kind())
+                .collect(),
+        }
+    }
+    /// Heals and effect changes since the snapshot, HP losses are left to the caller
+    pub fn changes(&self, unit: &Unit, who: UnitRef, from: Option<UnitRef>) -> Vec<BattleEvent> {
+        let mut events = Vec::new();
+        let hp = unit.modified.hp.max(0) - self.hp.max(0);
+        if hp > 0 {
+            events.push(BattleEvent::Heal {
+                from,
+                to: who,
+                hp: hp as u64,
+            });
+        }
+        let mut expired = self.effects.clone();
+        for effect in &unit.effects {
+            let kind = effect.get_kind();
+            match expired.iter().position(|old| *old == kind) {
+                Some(index) => {
+                    expired.remove(index);
+                }
+                None => events.push(BattleEvent::EffectAdded { unit: who, kind }),
+            }
+        }
+        events.extend(
+            expired
+                .into_iter()
+                .map(|kind| BattleEvent::EffectExpired { unit: who, kind }),
+        );
+        events
+    }
+}
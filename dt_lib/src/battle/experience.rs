@@ -0,0 +1,299 @@
+use crate::{
+    battle::{
+        army::{Army, MAX_TROOPS},
+        battlefield::{field_type, BattleInfo, Field},
+    },
+    items::item::Item,
+    units::unit::{calclate_unit_power, LevelUpReport, UnitPos},
+};
+use alkahest::alkahest;
+use math_thingies::Percent;
+
+/// How much tactic cost one point of unit power gives
+const TACTIC_COST_MULTIPLIER: f32 = 10.;
+/// Experience correction for armies without `PC_ControlSetings::xp_correction` (ExpCorrection)
+const XP_CORRECTION: Percent = Percent::const_new(100);
+
+/// What a troop did during the current battle
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct TroopBattleStats {
+    /// HP lost by the troop in the battle (UnitLostHit)
+    pub lost_hp: u64,
+    /// Moves spent on attacks, spells and movement (UseMov)
+    pub used_moves: u64,
+    /// Moves the troop had during the battle (AllMov)
+    pub all_moves: u64,
+}
+
+/// What an army did during the current battle
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct ArmyBattleStats {
+    /// Tactic cost of the army when the battle started (FirstTacticCost)
+    pub first_tactic_cost: u64,
+    /// Count of troops when the battle started (FirstMaxUnits)
+    pub first_units: u64,
+    /// HP of all troops when the battle started (AllHit)
+    pub all_hp: u64,
+    /// HP lost by the army (LostHit)
+    pub lost_hp: u64,
+    /// HP taken by the army from the enemy (NormalLostHit)
+    pub dealt_hp: u64,
+    /// Most HP taken from the enemy in one turn (TurnMaxHit)
+    pub turn_max_hp: u64,
+    /// HP taken from the enemy in the current turn
+    pub turn_dealt_hp: u64,
+}
+impl ArmyBattleStats {
+    pub fn new(army: &Army) -> Self {
+        let alive = army.troops.iter().filter(|troop| !troop.get().is_dead());
+        Self {
+            first_tactic_cost: tactic_cost(army),
+            first_units: alive.clone().count() as u64,
+            all_hp: alive
+                .map(|troop| troop.get().unit.modified.hp.max(0) as u64)
+                .sum(),
+            ..Default::default()
+        }
+    }
+    /// Adds statistics of another army, for the whole side
+    pub fn merge(&mut self, other: &Self) {
+        self.first_tactic_cost += other.first_tactic_cost;
+        self.first_units += other.first_units;
+        self.all_hp += other.all_hp;
+        self.lost_hp += other.lost_hp;
+        self.dealt_hp += other.dealt_hp;
+        self.turn_max_hp = self.turn_max_hp.max(other.turn_max_hp);
+        self.turn_dealt_hp += other.turn_dealt_hp;
+    }
+    pub fn end_turn(&mut self) {
+        self.turn_max_hp = self.turn_max_hp.max(self.turn_dealt_hp);
+        self.turn_dealt_hp = 0;
+    }
+}
+
+/// Experience that one troop got in a battle
+#[derive(Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct TroopSummary {
+    pub index: usize,
+    pub name: String,
+    pub xp: u64,
+    pub stats: TroopBattleStats,
+    pub level_up: Option<LevelUpReport>,
+}
+
+/// Result of a battle for one army
+#[derive(Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct ArmySummary {
+    pub army: usize,
+    pub xp: u64,
+    /// Experience by the old formula of the original game, see `old_army_experience`
+    pub old_xp: u64,
+    pub stats: ArmyBattleStats,
+    pub troops: Vec<TroopSummary>,
+    /// Troops of the army that died in the battle
+    pub casualties: usize,
+    /// Troops promoted after the battle, as troop index and unit index
+    pub promoted: Vec<(usize, usize)>,
+}
+
+/// Result of a battle, ready to be shown to the player
+#[derive(Clone, Debug, Default)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct BattleSummary {
+    pub winner: Option<usize>,
+    /// Army that retreated, it pays the penalty instead of losing everything
+    pub fled: Option<usize>,
+    pub armies: Vec<ArmySummary>,
+    pub items: Vec<Item>,
+    pub gold: u64,
+    pub mana: u64,
+    /// Count of troops taken prisoner, see `BattleInfo::prisoners`
+    pub prisoners: usize,
+}
+
+/// Tactic cost (power) of the army, wounded troops cost less (CalkArmyCost)
+pub fn tactic_cost(army: &Army) -> u64 {
+    army.troops
+        .iter()
+        .map(|troop| {
+            let unit = &troop.get().unit;
+            if unit.is_dead() {
+                return 0.;
+            }
+            let health = unit.modified.hp as f32 / unit.modified.max_hp.max(1) as f32;
+            calclate_unit_power(unit) * health.min(1.) * TACTIC_COST_MULTIPLIER
+        })
+        .sum::<f32>()
+        .round() as u64
+}
+
+/// Row of the troop as the original game counts it: 1 - front, 2 - back, 3 - reserve
+pub fn troop_row(pos: UnitPos) -> u64 {
+    match field_type(pos.into(), *MAX_TROOPS) {
+        Field::Front => 1,
+        Field::Back => 2,
+        Field::Reserve => 3,
+    }
+}
+
+/// Enemy power against the army power, moved away from 1 by `correction` percents
+/// and kept within 0.25..=4 (dExp)
+pub fn power_ratio(stats: &ArmyBattleStats, enemy: &ArmyBattleStats, correction: Percent) -> f32 {
+    if stats.first_tactic_cost == 0 {
+        // An army without power gets the most, unless the enemy has none either
+        return if enemy.first_tactic_cost == 0 { 1. } else { 4. };
+    }
+    let ratio = enemy.first_tactic_cost as f32 / stats.first_tactic_cost as f32;
+    let correction = correction.get() as f32 / 100.;
+    let ratio = if ratio >= 1. {
+        1. + (ratio - 1.) * correction
+    } else {
+        1. - (1. - ratio) * correction
+    };
+    ratio.clamp(0.25, 4.)
+}
+
+/// Enemy power destroyed in the battle times the power ratio (OldNewExpirience),
+/// weak armies beating strong ones get more. `enemy_tactic_cost` is the enemy power left
+pub fn old_army_experience(
+    stats: &ArmyBattleStats,
+    enemy: &ArmyBattleStats,
+    enemy_tactic_cost: u64,
+    ratio: f32,
+) -> u64 {
+    if stats.first_units == 0 || stats.first_tactic_cost == 0 {
+        return 0;
+    }
+    let destroyed = enemy.first_tactic_cost.saturating_sub(enemy_tactic_cost);
+    (destroyed as f32 * ratio).round() as u64
+}
+
+/// Experience of the whole army, based on the enemy power and on how hard the battle was
+pub fn army_experience(stats: &ArmyBattleStats, enemy: &ArmyBattleStats) -> u64 {
+    if stats.first_units == 0 {
+        return 0;
+    }
+    let base_xp = (enemy.first_tactic_cost / 20) as f32;
+    let dealt = stats.dealt_hp as f32;
+    let turn_max = stats.turn_max_hp.max(stats.turn_dealt_hp) as f32;
+    let xp = if stats.lost_hp > 0 {
+        if stats.dealt_hp == 0 {
+            let survived =
+                (stats.all_hp as f32 - stats.lost_hp as f32) / stats.all_hp.max(1) as f32;
+            base_xp * survived.max(0.)
+        } else {
+            let difficulty = (dealt / stats.lost_hp as f32).clamp(0.8, 3.);
+            base_xp + dealt * difficulty + turn_max
+        }
+    } else {
+        base_xp + dealt * 3. + turn_max
+    };
+    xp.round() as u64
+}
+
+/// Experience scaled by the correction percents, negative corrections give nothing
+pub fn corrected_experience(xp: u64, correction: Percent) -> u64 {
+    (xp as f32 * correction.get().max(0) as f32 / 100.).round() as u64
+}
+
+/// Share of the army experience for one troop, troops behind get more for being active
+pub fn troop_experience(army_xp: u64, first_units: u64, row: u64, stats: &TroopBattleStats) -> u64 {
+    if first_units == 0 {
+        return 0;
+    }
+    let share = 0.25 * army_xp as f32 / first_units as f32;
+    let row = row as f32;
+    let mut xp = share;
+    if stats.all_moves > 0 {
+        let activity = stats.used_moves.min(stats.all_moves) as f32 / stats.all_moves as f32;
+        xp = (4. - row) * share + row * share * activity;
+    }
+    if xp < 0.5 {
+        xp = 1.;
+    }
+    xp.round() as u64
+}
+
+/// Gives experience to every alive troop of all armies and levels them up.
+/// Every army is measured against the whole enemy side.
+/// Army experience and the power ratio of the old formula are corrected by
+/// `PC_ControlSetings::xp_correction`
+pub fn distribute_experience(battle: &BattleInfo, armys: &mut Vec<Army>) -> Vec<ArmySummary> {
+    let tactic_costs: Vec<u64> = battle
+        .armies
+        .iter()
+        .map(|army| tactic_cost(&armys[*army]))
+        .collect();
+    battle
+        .armies
+        .iter()
+        .enumerate()
+        .map(|(slot, &army_index)| {
+            let stats = battle.stats.get(slot).copied().unwrap_or_default();
+            let side = battle.side(army_index);
+            let mut enemy = ArmyBattleStats::default();
+            let mut enemy_tactic_cost = 0;
+            for ((other, other_stats), cost) in
+                battle.armies.iter().zip(&battle.stats).zip(&tactic_costs)
+            {
+                if battle.side(*other) != side {
+                    enemy.merge(other_stats);
+                    enemy_tactic_cost += cost;
+                }
+            }
+            let army = &mut armys[army_index];
+            let correction = army
+                .pc_settings
+                .as_ref()
+                .map(|settings| settings.xp_correction)
+                .filter(|correction| correction.get() != 0)
+                .unwrap_or(XP_CORRECTION);
+            let ratio = power_ratio(&stats, &enemy, correction);
+            let old_xp = old_army_experience(&stats, &enemy, enemy_tactic_cost, ratio);
+            let xp = corrected_experience(army_experience(&stats, &enemy), correction);
+            let casualties = army
+                .troops
+                .iter()
+                .chain(&battle.dead)
+                .filter(|troop| {
+                    let troop = troop.get();
+                    troop.is_dead() && troop.unit.army == army_index
+                })
+                .count();
+            let troops = army
+                .troops
+                .iter()
+                .enumerate()
+                .filter_map(|(index, troop)| {
+                    let mut troop = troop.get();
+                    if troop.is_dead() {
+                        return None;
+                    }
+                    let troop_stats = troop.battle_stats;
+                    let troop_xp =
+                        troop_experience(xp, stats.first_units, troop_row(troop.pos), &troop_stats);
+                    Some(TroopSummary {
+                        index,
+                        name: troop.unit.info.name.clone(),
+                        xp: troop_xp,
+                        stats: troop_stats,
+                        level_up: troop.unit.add_xp(troop_xp),
+                    })
+                })
+                .collect();
+            ArmySummary {
+                army: army_index,
+                xp,
+                old_xp,
+                stats,
+                troops,
+                casualties,
+                promoted: Vec::new(),
+            }
+        })
+        .collect()
+}
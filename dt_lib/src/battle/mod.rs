@@ -1,6 +1,12 @@
+pub mod ai;
 pub mod army;
 pub mod battlefield;
 pub mod control;
+pub mod event;
+pub mod experience;
+pub mod rules;
+pub mod siege;
+pub mod terrain;
 pub mod troop;
 pub use army::*;
 pub use battlefield::*;
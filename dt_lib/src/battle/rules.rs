@@ -0,0 +1,95 @@
+use crate::parse::SETTINGS;
+use advini::{Ini, IniParseError, Section, SectionError, Sections};
+use alkahest::alkahest;
+use once_cell::sync::Lazy;
+
+/// Rules of battles, read from the [Battle] section of Settings.ini.
+/// A map can override them with its own [Battle] section, see `GameMap::battle_rules`
+#[derive(Clone, Debug, Sections)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct BattleRules {
+    /// Turns after which the battle ends by timeout
+    #[default_value = "25_u64"]
+    pub max_moves: u64,
+    /// Side that wins on timeout: 0 for the side of army1, 1 for the side of army2
+    #[default_value = "0_usize"]
+    pub timeout_winner: usize,
+}
+impl BattleRules {
+    pub const fn new() -> Self {
+        Self {
+            max_moves: 25,
+            timeout_winner: 0,
+        }
+    }
+}
+impl Default for BattleRules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Part of the [Battle] section of Settings.ini that maps can not change:
+/// armies keep their field between battles
+#[derive(Clone, Debug, Sections)]
+pub struct BattleSettings {
+    /// Lines of the battlefield, the last one is the front
+    #[default_value = "2_usize"]
+    pub max_lines: usize,
+    /// Reserve cells of the first line, the first and the last cell if empty
+    #[default_value = "Vec::new()"]
+    pub reserve: Vec<usize>,
+    /// Multiplier of the hire cost of recruits, 1 keeps the prices of Units.ini.
+    /// See `Recruitment::cost`
+    #[default_value = "1_f64"]
+    pub recruit_cost: f64,
+}
+impl BattleSettings {
+    pub const fn new() -> Self {
+        Self {
+            max_lines: 2,
+            reserve: Vec::new(),
+            recruit_cost: 1.,
+        }
+    }
+    /// Whether the cell of a field with `max_troops` cells is in the reserve
+    pub fn is_reserve(&self, index: usize, max_troops: usize) -> bool {
+        let columns = max_troops / self.max_lines.max(1);
+        if self.reserve.is_empty() {
+            index == 0 || index + 1 == columns
+        } else {
+            self.reserve.contains(&index)
+        }
+    }
+}
+impl Default for BattleSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rules from Settings.ini
+pub static BATTLE_RULES: Lazy<BattleRules> = Lazy::new(|| unsafe { &SETTINGS }.battle.clone());
+/// Battlefield and recruiting settings from Settings.ini
+pub static BATTLE_SETTINGS: Lazy<BattleSettings> =
+    Lazy::new(|| unsafe { &SETTINGS }.battle_settings.clone());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn battle_section() {
+        let ini = "[Battle]\nMAX_MOVES = 40\nTIMEOUT_WINNER = 1\nRESERVE = 2,3\n";
+        let (_, section) = advini::parse_for_sections(ini).remove(0);
+        let (rules, remaining) = BattleRules::from_section(section).unwrap();
+        assert_eq!(rules.max_moves, 40);
+        assert_eq!(rules.timeout_winner, 1);
+        let (settings, _) = BattleSettings::from_section(remaining).unwrap();
+        assert_eq!(settings.max_lines, 2);
+        assert_eq!(settings.recruit_cost, 1.);
+        assert!(settings.is_reserve(2, 12) && settings.is_reserve(3, 12));
+        assert!(!settings.is_reserve(0, 12));
+        assert!(BattleSettings::new().is_reserve(0, 12) && BattleSettings::new().is_reserve(5, 12));
+    }
+}
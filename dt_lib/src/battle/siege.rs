@@ -0,0 +1,193 @@
+use crate::{
+    battle::{
+        army::{Army, ArmyStats},
+        battlefield::BattleInfo,
+        control::Control,
+        event::{unit_ref, BattleEvent},
+        troop::Troop,
+    },
+    bonuses::Bonus,
+    effects::effect::{EffectInfo, ToEndEffect},
+    map::map::{Capture, GameMap},
+    units::{
+        unit::Unit,
+        unitstats::{Modify, ModifyDefence, ModifyUnitStats},
+    },
+};
+use alkahest::alkahest;
+
+/// Battle against the garrison of a building
+#[derive(Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct Siege {
+    pub building: usize,
+    /// Temporary army made of the garrison, `end_siege` removes it
+    pub garrison: usize,
+    pub additional_defense: u64,
+    /// Garrison units that did not fit into the army, they stay in the building
+    pub left_out: Vec<Unit>,
+    /// The result is already written back to the building
+    pub resolved: bool,
+}
+impl Siege {
+    /// Gives `Bonus::Garrison` and `additional_defense` to every troop of the garrison
+    pub fn fortify(&self, army: &mut Army, battle: &BattleInfo) {
+        for troop in &army.troops {
+            let troop = &mut troop.get();
+            let pos = troop.pos;
+            let unit = &mut troop.unit;
+            if !matches!(unit.get_bonus(), Bonus::Garrison)
+                && Bonus::Garrison.on_battle_start(unit, battle)
+            {
+                battle.emit(BattleEvent::BonusTriggered {
+                    unit: unit_ref(unit, pos),
+                    bonus: Bonus::Garrison,
+                });
+            }
+            if self.additional_defense > 0 {
+                let defense = self.additional_defense as i64;
+                unit.add_effect(ToEndEffect {
+                    info: EffectInfo { lifetime: i32::MAX },
+                    modify: ModifyUnitStats {
+                        defence: ModifyDefence {
+                            hand_units: *Modify::default().add(defense),
+                            ranged_units: *Modify::default().add(defense),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                });
+            }
+        }
+    }
+}
+
+/// Building the army can besiege from where it stands: a building next to the army
+/// that has a garrison and can be captured, see `GameMap::can_capture`
+pub fn siege_target(gamemap: &GameMap, army: usize, goal: (usize, usize)) -> Option<usize> {
+    let pos = gamemap.armys.get(army)?.pos;
+    let diff = (pos.0 as i64 - goal.0 as i64, pos.1 as i64 - goal.1 as i64);
+    if diff.0.abs() > 1 || diff.1.abs() > 1 {
+        return None;
+    }
+    let building = gamemap.hitmap[goal].building?;
+    let data = gamemap.buildings.get(building)?;
+    if !gamemap.can_capture(building, army) || data.garrison.is_empty() {
+        return None;
+    }
+    Some(building)
+}
+
+/// Starts a battle of the army against the garrison of the building.
+/// The garrison becomes a temporary inactive army, see `end_siege`
+pub fn start_siege(
+    gamemap: &mut GameMap,
+    attacker: usize,
+    building: usize,
+) -> Result<BattleInfo, ()> {
+    let data = gamemap.buildings.get(building).ok_or(())?;
+    if !gamemap.can_capture(building, attacker) || data.garrison.is_empty() {
+        return Err(());
+    }
+    let index = gamemap
+        .armys
+        .iter()
+        .position(|army| !army.active && army.building == Some(building))
+        .unwrap_or(gamemap.armys.len());
+    let mut army = Army::new(
+        Vec::new(),
+        ArmyStats {
+            army_name: data.name.clone(),
+            ..Default::default()
+        },
+        Vec::new(),
+        data.pos,
+        false,
+        Control::PC,
+    );
+    army.building = Some(building);
+    let mut left_out = Vec::new();
+    for unit in &data.garrison {
+        let mut unit = unit.clone();
+        unit.army = index;
+        if army.add_troop(Troop::new(unit.clone()).into()).is_err() {
+            left_out.push(unit);
+        }
+    }
+    let siege = Siege {
+        building,
+        garrison: index,
+        additional_defense: data.additional_defense,
+        left_out,
+        resolved: false,
+    };
+    if index == gamemap.armys.len() {
+        gamemap.armys.push(army);
+    } else {
+        gamemap.armys[index] = army;
+    }
+    let mut battle = BattleInfo::setup_siege(attacker, siege);
+    battle.rules = gamemap.battle_rules.clone();
+    battle.battle_ter = gamemap.battle_terrain(gamemap.armys[index].pos);
+    battle.start(&mut gamemap.armys);
+    Ok(battle)
+}
+
+/// Writes the result of an ended siege back to the building.
+/// If the attacker won, the garrison is gone and the attacker captures the building,
+/// see `GameMap::transfer_building`, the units left out of the battle become prisoners.
+/// Otherwise the surviving garrison returns to the building.
+/// Goods the garrison won go to the owner of the building.
+/// The temporary garrison army is removed when it is the last army, armies are referred to
+/// by index, so one in the middle stays empty until `start_siege` reuses it
+pub fn end_siege(gamemap: &mut GameMap, battle: &mut BattleInfo) -> Result<Option<Capture>, ()> {
+    if battle.summary.is_none() {
+        return Err(());
+    }
+    let winner = battle.winner.ok_or(())?;
+    let siege = battle
+        .siege
+        .as_mut()
+        .filter(|siege| !siege.resolved)
+        .ok_or(())?;
+    siege.resolved = true;
+    let garrison = &mut gamemap.armys[siege.garrison];
+    let survivors = garrison
+        .troops
+        .drain(..)
+        .filter_map(|troop| {
+            let mut troop = troop.get().clone();
+            if troop.is_dead() {
+                return None;
+            }
+            troop.on_battle_end();
+            Some(troop.unit)
+        })
+        .collect::<Vec<_>>();
+    garrison.recalc_army_hitmap();
+    let gold = std::mem::take(&mut garrison.stats.gold);
+    let mana = std::mem::take(&mut garrison.stats.mana);
+    let items = std::mem::take(&mut garrison.inventory);
+    if siege.garrison + 1 == gamemap.armys.len() {
+        gamemap.armys.pop();
+    }
+    let data = &mut gamemap.buildings[siege.building];
+    if winner == siege.garrison {
+        data.garrison = survivors;
+        data.garrison.extend(siege.left_out.drain(..));
+        if let Some(owner) = data.owner.and_then(|owner| gamemap.armys.get_mut(owner)) {
+            owner.stats.gold += gold;
+            owner.stats.mana += mana;
+            owner.inventory.extend(items);
+        }
+        Ok(None)
+    } else {
+        data.garrison.clear();
+        let captured = siege.left_out.drain(..).map(|unit| Troop::new(unit).into());
+        battle.prisoners.extend(captured);
+        if let Some(summary) = &mut battle.summary {
+            summary.prisoners = battle.prisoners.len();
+        }
+        Ok(Some(gamemap.transfer_building(siege.building, winner)))
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{
+    effects::effect::{EffectInfo, ToEndEffect},
+    map::object::BuildingVariant,
+    units::unitstats::ModifyUnitStats,
+};
+use once_cell::sync::Lazy;
+use tracing_mutex::stdsync::TracingMutex as Mutex;
+
+/// Terrain a battle is fought on, read from Terrains.ini by `parse_terrains`
+#[derive(Clone, Debug, Default)]
+pub struct TerrainInfo {
+    pub name: String,
+    pub description: String,
+    /// Indexes in `TILES` of the tiles with this terrain
+    pub tiles: Vec<usize>,
+    /// Building variants with this terrain, they win over the tile under the building
+    pub buildings: Vec<String>,
+    /// Change of stats of every troop for the whole battle
+    pub modify: ModifyUnitStats,
+}
+impl TerrainInfo {
+    /// Effect the terrain gives to every troop, it ends with the battle
+    pub fn effect(&self) -> ToEndEffect {
+        ToEndEffect {
+            info: EffectInfo { lifetime: i32::MAX },
+            modify: self.modify,
+        }
+    }
+}
+pub static TERRAINS: Lazy<Mutex<Vec<TerrainInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Terrain of a battle on the tile, the building on it is checked first
+pub fn terrain_of(tile: usize, building: Option<&BuildingVariant>) -> Option<usize> {
+    let terrains = TERRAINS.lock().unwrap();
+    building
+        .and_then(|building| {
+            terrains.iter().position(|terrain| {
+                terrain
+                    .buildings
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(building.name()))
+            })
+        })
+        .or_else(|| {
+            terrains
+                .iter()
+                .position(|terrain| terrain.tiles.contains(&tile))
+        })
+}
@@ -1,6 +1,11 @@
 use crate::units::unitstats::ModifyUnitStats;
 
-use crate::{battle::army::Army, bonuses::Bonus, effects::effect::EffectTrait, units::unit::*};
+use crate::{
+    battle::{army::Army, experience::TroopBattleStats},
+    bonuses::Bonus,
+    effects::effect::EffectTrait,
+    units::unit::*,
+};
 use alkahest::alkahest;
 use std::fmt::{Debug, Display, Formatter};
 
@@ -13,6 +18,7 @@ pub struct Troop {
     pub pos: UnitPos,
     pub custom_name: Option<String>,
     pub unit: Unit,
+    pub battle_stats: TroopBattleStats,
 }
 impl Debug for Troop {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -32,6 +38,7 @@ impl Troop {
             pos: UnitPos::from_index(0),
             custom_name: None,
             unit,
+            battle_stats: TroopBattleStats::default(),
         }
     }
     pub fn on_pay(&self, army: &mut Army) -> u64 {
@@ -46,22 +53,51 @@ impl Troop {
     pub fn on_battle_end(&mut self) {
         let unit = &mut self.unit;
         let mut i = 0;
-        loop {
+        while i < unit.effects.len() {
             if unit.effects[i].on_battle_end() && unit.effects[i].is_dead() {
                 let mut effect = unit.effects.remove(i);
                 effect.kill(unit);
-                i -= 1;
-            };
-            if i + 1 >= unit.effects.len() {
-                break;
+            } else {
+                i += 1;
             }
-            i += 1;
         }
         unit.recalc();
     }
     pub fn is_dead(&self) -> bool {
         self.unit.is_dead()
     }
+    /// Cells the troop covers on the battlefield, its position first
+    pub fn cells(&self) -> Vec<UnitPos> {
+        let (pos, size) = (self.pos, self.unit.info.size);
+        (0..size.1)
+            .flat_map(|j| (0..size.0).map(move |i| UnitPos(pos.0 + i, pos.1 + j)))
+            .collect()
+    }
+    /// Indexes of units in `units` this troop can be promoted to at its current level
+    pub fn promotions(&self, units: &Vec<Unit>) -> Vec<usize> {
+        let info = &self.unit.info;
+        info.next_unit
+            .iter()
+            .zip(&info.next_unit_lvl)
+            .filter(|(unit, lvl)| self.unit.lvl.lvl >= **lvl && **unit < units.len())
+            .map(|(unit, _)| *unit)
+            .collect()
+    }
+    /// Turns the troop into `unit`, keeping its name, experience, inventory, effects and wounds.
+    /// Does not check the army field, use `Army::promote_troop` for that
+    pub fn promote(&mut self, unit: &Unit) {
+        let old = &self.unit;
+        let wounds = (old.stats.max_hp - old.stats.hp).max(0);
+        let mut new = unit.clone();
+        new.army = old.army;
+        new.lvl.xp = old.lvl.xp;
+        new.modify = old.modify;
+        new.inventory = old.inventory.clone();
+        new.effects = old.effects.clone();
+        new.stats.hp = (new.stats.max_hp - wounds).max(1);
+        new.recalc();
+        self.unit = new;
+    }
     pub fn empty() -> Self {
         Self {
             was_payed: true,
@@ -80,6 +116,7 @@ impl Troop {
                 bonus: Bonus::NoBonus,
                 effects: vec![],
             },
+            battle_stats: TroopBattleStats::default(),
         }
     }
 }
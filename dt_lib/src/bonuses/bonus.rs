@@ -1,5 +1,9 @@
 use crate::{
-    battle::{army::Army, battlefield::BattleInfo},
+    battle::{
+        army::{Army, MAX_LINES, MAX_TROOPS},
+        battlefield::BattleInfo,
+        event::{unit_ref, BattleEvent},
+    },
     effects::effect::*,
     time::time::Time,
     units::{
@@ -45,7 +49,20 @@ pub enum Bonus {
     Custom = { u32::MAX - 1 },
     NoBonus = u32::MAX,
 }
+/// Share of max HP an `ArmyMedic` restores to every troop of its army each 12 hours
+const MEDIC_ARMY_HEAL: Percent = Percent::const_new(10);
+/// Share of max HP an `ArmyMedic` restores to itself each battle round
+const MEDIC_SELF_HEAL: Percent = Percent::const_new(5);
+/// Discount a `Merchant` gets on the market
+const MERCHANT_DISCOUNT: Percent = Percent::const_new(25);
+
 impl Bonus {
+    fn triggered(&self, unit: &Unit, pos: UnitPos, battle: &BattleInfo) {
+        battle.emit(BattleEvent::BonusTriggered {
+            unit: unit_ref(unit, pos),
+            bonus: *self,
+        });
+    }
     pub fn on_attacked(
         &self,
         damage: Power,
@@ -61,6 +78,7 @@ impl Bonus {
             | Self::DeadDodging
             | Self::Dodging
             | Self::Garrison => {
+                self.triggered(receiver, receiver_pos, battle);
                 let percent_70 = Percent::new(70);
                 Power {
                     magic: percent_70.calc(damage.magic),
@@ -68,11 +86,14 @@ impl Bonus {
                     hand: percent_70.calc(damage.hand),
                 }
             }
-            Self::Invulrenable => Power {
-                hand: min(1, damage.hand),
-                ranged: min(1, damage.ranged),
-                magic: damage.magic,
-            },
+            Self::Invulrenable => {
+                self.triggered(receiver, receiver_pos, battle);
+                Power {
+                    hand: min(1, damage.hand),
+                    ranged: min(1, damage.ranged),
+                    magic: damage.magic,
+                }
+            }
             Self::Ghost => {
                 let mut corrected_damage_units = damage.magic;
                 if corrected_damage_units == 0 {
@@ -83,6 +104,7 @@ impl Bonus {
                         <= 30 * (sender.modified.max_moves as i16)
                     {
                         sender.kill();
+                        self.triggered(receiver, receiver_pos, battle);
                     }
                 }
                 Power {
@@ -97,6 +119,7 @@ impl Bonus {
                 }
                 if (receiver.modified.hp as i64 - corrected_damage_units as i64) < 1 {
                     sender.kill();
+                    self.triggered(receiver, receiver_pos, battle);
                 }
                 damage
             }
@@ -110,6 +133,7 @@ impl Bonus {
                 {
                     let hp = receiver.modified.hp;
                     receiver.add_effect(RessurectedEffect::new());
+                    self.triggered(receiver, receiver_pos, battle);
                     Power {
                         hand: hp as u64,
                         ..Power::empty()
@@ -119,11 +143,35 @@ impl Bonus {
                 }
             }
             Self::Counterblow => {
-                sender.attack(receiver, receiver_pos, sender_pos, battle);
+                if sender
+                    .attack(receiver, receiver_pos, sender_pos, battle)
+                    .is_some()
+                {
+                    self.triggered(receiver, receiver_pos, battle);
+                }
                 damage
             }
             Self::Stealth => {
                 if receiver.modified.moves == receiver.modified.max_moves {
+                    self.triggered(receiver, receiver_pos, battle);
+                    Power::empty()
+                } else {
+                    damage
+                }
+            }
+            // Strikes a melee attacker first, a dead attacker deals no damage.
+            // Attackers that answer blows themselves are left alone, or the blows never end
+            Self::SpearDefence => {
+                if damage.hand == 0
+                    || matches!(sender.get_bonus(), Self::SpearDefence | Self::Counterblow)
+                    || receiver
+                        .attack(sender, sender_pos, receiver_pos, battle)
+                        .is_none()
+                {
+                    return damage;
+                }
+                self.triggered(receiver, receiver_pos, battle);
+                if sender.is_dead() {
                     Power::empty()
                 } else {
                     damage
@@ -139,18 +187,23 @@ impl Bonus {
         sender: &mut Unit,
         receiver_pos: UnitPos,
         sender_pos: UnitPos,
+        battle: &BattleInfo,
     ) -> Power {
         match self {
             Self::DefencePiercing
             | Self::VampiresGist
             | Self::AncientVampiresGist
-            | Self::Artillery => pierce(sender),
+            | Self::Artillery => {
+                self.triggered(sender, sender_pos, battle);
+                pierce(sender)
+            }
             Self::PoisonAttack => {
                 if !receiver.has_effect_kind(EffectKind::Poison)
                     && receiver.info.unit_type != UnitType::Undead
                 {
                     if damage.ranged > 1 || damage.hand > 1 {
                         receiver.add_effect(Poison::default());
+                        self.triggered(sender, sender_pos, battle);
                     }
                 }
                 damage
@@ -159,15 +212,18 @@ impl Bonus {
                 if !receiver.has_effect_kind(EffectKind::Fire) {
                     if damage.ranged > 1 || damage.hand > 1 {
                         receiver.add_effect(Fire::default());
+                        self.triggered(sender, sender_pos, battle);
                     } else if damage.magic > 1
                         && matches!(sender.info.magic_type, Some(MagicType::Elemental(_)))
                     {
                         receiver.add_effect(Fire::new(sender.modified.damage.magic as i64));
+                        self.triggered(sender, sender_pos, battle);
                     }
                 }
                 damage
             }
             Self::GodAnger => {
+                self.triggered(sender, sender_pos, battle);
                 damage
                     + Power {
                         hand: 10,
@@ -175,6 +231,7 @@ impl Bonus {
                     }
             }
             Self::GodStrike => {
+                self.triggered(sender, sender_pos, battle);
                 damage
                     + Power {
                         hand: 20,
@@ -183,6 +240,7 @@ impl Bonus {
             }
             Self::FlankStrike => {
                 if damage.hand > 0 && (receiver_pos.0 as i64 - sender_pos.0 as i64).abs() > 1 {
+                    self.triggered(sender, sender_pos, battle);
                     Power {
                         hand: {
                             sender
@@ -220,14 +278,34 @@ impl Bonus {
             _ => false,
         }
     }
+    /// Called every battle round for the unit with the bonus
     pub fn on_tick(&self, unit: &mut Unit) -> bool {
         match self {
+            Self::ArmyMedic => {
+                if !can_be_treated(unit) {
+                    return false;
+                }
+                unit.heal(MEDIC_SELF_HEAL.calc(unit.modified.max_hp.max(0) as u64));
+                true
+            }
             _ => false,
         }
     }
+    /// Called every 12 hours of world time for a troop of the army with the bonus.
+    /// Troops of the army must not be locked
     pub fn on_12_hour(&self, army: &Army) -> bool {
         match self {
-            Self::ArmyMedic => true,
+            Self::ArmyMedic => {
+                let mut healed = false;
+                for troop in &army.troops {
+                    let unit = &mut troop.get().unit;
+                    if can_be_treated(unit) {
+                        unit.heal(MEDIC_ARMY_HEAL.calc(unit.modified.max_hp.max(0) as u64));
+                        healed = true;
+                    }
+                }
+                healed
+            }
             _ => false,
         }
     }
@@ -243,6 +321,12 @@ impl Bonus {
                 });
                 true
             }
+            Self::SpearDefence => {
+                unit.add_effect(SpearEffect {
+                    info: EffectInfo { lifetime: 1 },
+                });
+                true
+            }
             Self::Garrison => unit.add_effect(ToEndEffect {
                 info: EffectInfo { lifetime: i32::MAX },
                 modify: ModifyUnitStats {
@@ -287,6 +371,27 @@ impl Bonus {
             _ => false,
         }
     }
+    /// Discount on the market for the army of the unit, see `Army::trade_discount`
+    pub fn trade_discount(&self) -> Percent {
+        match self {
+            Self::Merchant => MERCHANT_DISCOUNT,
+            _ => Percent::new(0),
+        }
+    }
+    /// Cells of the target row hit by the same attack besides the target cell
+    pub fn extra_targets(&self, target_pos: UnitPos) -> Vec<UnitPos> {
+        match self {
+            Self::ManyTargets => {
+                let columns = *MAX_TROOPS / *MAX_LINES;
+                [target_pos.0.wrapping_sub(1), target_pos.0 + 1]
+                    .into_iter()
+                    .filter(|column| *column < columns)
+                    .map(|column| UnitPos(column, target_pos.1))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
     pub fn can_attack_from_reserve(&self) -> bool {
         match self {
             Self::Ghost => true,
@@ -313,9 +418,11 @@ impl Bonus {
             Self::GodAnger => ("bonus_godanger", "bonus_godanger_desc"),
             Self::GodStrike => ("bonus_godstrike", "bonus_godstrike_desc"),
             Self::Invulrenable => ("bonus_invulrenable", "bonus_invulrenable_desc"),
-            Self::ManyTargets => ("bonus_manytargets", "bonus_manytargets"),
-            Self::Merchant => ("bonus_merchant", "bonus_merchant"),
+            Self::ManyTargets => ("bonus_manytargets", "bonus_manytargets_desc"),
+            Self::Merchant => ("bonus_merchant", "bonus_merchant_desc"),
             Self::PoisonAttack => ("bonus_poison", "bonus_poison_desc"),
+            Self::SpearDefence => ("bonus_speardefense", "bonus_speardefense_desc"),
+            Self::ArmyMedic => ("bonus_armymedic", "bonus_armymedic_desc"),
             _ => ("", ""),
         }
     }
@@ -369,6 +476,13 @@ impl From<&str> for Bonus {
     }
 }
 
+/// Medics treat living wounded troops only
+fn can_be_treated(unit: &Unit) -> bool {
+    !unit.is_dead()
+        && unit.modified.hp < unit.modified.max_hp
+        && !matches!(unit.info.unit_type, UnitType::Undead | UnitType::Mecha)
+}
+
 fn pierce(sender: &mut Unit) -> Power {
     let sender_damage: Power = sender.modified.damage;
     if sender_damage.magic > sender_damage.ranged && sender_damage.magic > sender_damage.hand {
@@ -392,3 +506,114 @@ fn pierce(sender: &mut Unit) -> Power {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::{
+            battlefield::{handle_action, Action},
+            troop::Troop,
+        },
+        items::item::Item,
+        map::object::Market,
+        parse::parse_items,
+        test_helpers::{self, army_of, wounded},
+    };
+
+    fn knight(bonus: Bonus, army: usize) -> Unit {
+        let mut unit = test_helpers::knight();
+        unit.bonus = bonus;
+        unit.army = army;
+        unit.recalc();
+        unit
+    }
+    #[test]
+    fn spear_defence() {
+        let battle = BattleInfo::default();
+        let mut spearman = knight(Bonus::SpearDefence, 0);
+        assert!(Bonus::SpearDefence.on_battle_start(&mut spearman, &battle));
+        assert_eq!(spearman.modified.defence.hand_units, 45);
+        spearman.tick();
+        assert_eq!(spearman.modified.defence.hand_units, 15);
+
+        // The spearman strikes first, an attacker killed by it deals no damage
+        let mut attacker = knight(Bonus::NoBonus, 1);
+        attacker.attack(&mut spearman, UnitPos(1, 1), UnitPos(0, 1), &battle);
+        assert_eq!((attacker.modified.hp, spearman.modified.hp), (50, 50));
+        let mut attacker = wounded(knight(Bonus::NoBonus, 1), 20);
+        let mut spearman = knight(Bonus::SpearDefence, 0);
+        attacker.attack(&mut spearman, UnitPos(1, 1), UnitPos(0, 1), &battle);
+        assert!(attacker.is_dead());
+        assert_eq!(spearman.modified.hp, 80);
+    }
+    #[test]
+    fn many_targets() {
+        let mut sweeper = knight(Bonus::ManyTargets, 0);
+        sweeper.stats.speed = 20;
+        sweeper.recalc();
+        let mut armys = vec![
+            army_of(vec![Troop::new(sweeper)], 0),
+            army_of(
+                (0..4)
+                    .map(|_| Troop::new(knight(Bonus::NoBonus, 1)))
+                    .collect(),
+                0,
+            ),
+        ];
+        armys[0].move_troop(0, 6).unwrap();
+        for troop in 0..4 {
+            armys[1].move_troop(troop, 7 + troop).unwrap();
+        }
+        let mut battle = BattleInfo::new(&mut armys, 0, 1);
+        assert_eq!(battle.active_unit, Some((0, 0)));
+        assert!(handle_action(Action::Cell(8, 1), &mut battle, &mut armys).is_some());
+        let hp: Vec<_> = armys[1]
+            .troops
+            .iter()
+            .map(|troop| troop.get().unit.modified.hp)
+            .collect();
+        assert_eq!(hp, vec![50, 50, 50, 80]);
+    }
+    #[test]
+    fn merchant() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let market = Market {
+            itemcost_range: (0, 1000),
+            items: vec![Item { index: 1 }],
+            max_items: 1,
+        };
+        let mut army = army_of(vec![Troop::new(knight(Bonus::NoBonus, 0))], 0);
+        assert_eq!(market.get_item_cost(0, &army), 260);
+        army.add_troop(Troop::new(knight(Bonus::Merchant, 0)).into())
+            .unwrap();
+        assert_eq!(army.trade_discount().get(), 25);
+        assert_eq!(market.get_item_cost(0, &army), 195);
+    }
+    #[test]
+    fn army_medic() {
+        let army = army_of(
+            vec![
+                Troop::new(knight(Bonus::ArmyMedic, 0)),
+                Troop::new(wounded(knight(Bonus::NoBonus, 0), 40)),
+            ],
+            0,
+        );
+        assert!(Bonus::ArmyMedic.on_12_hour(&army));
+        assert_eq!(army.troops[0].get().unit.modified.hp, 80);
+        assert_eq!(army.troops[1].get().unit.modified.hp, 48);
+        assert!(!Bonus::NoBonus.on_12_hour(&army));
+        let healthy = army_of(vec![Troop::new(knight(Bonus::ArmyMedic, 0))], 0);
+        assert!(!Bonus::ArmyMedic.on_12_hour(&healthy));
+    }
+    #[test]
+    fn medic_on_tick() {
+        let mut medic = wounded(knight(Bonus::ArmyMedic, 0), 40);
+        medic.tick();
+        assert_eq!(medic.modified.hp, 44);
+        let mut unit = wounded(knight(Bonus::NoBonus, 0), 40);
+        assert!(!Bonus::NoBonus.on_tick(&mut unit));
+        unit.tick();
+        assert_eq!(unit.modified.hp, 40);
+    }
+}
@@ -1,6 +1,9 @@
-use crate::units::{
-    unit::{Unit, *},
-    unitstats::*,
+use crate::{
+    time::time::Time,
+    units::{
+        unit::{Unit, *},
+        unitstats::*,
+    },
 };
 use alkahest::alkahest;
 use dyn_clone::DynClone;
@@ -8,7 +11,7 @@ use enum_dispatch::enum_dispatch;
 use math_thingies::Percent;
 use std::fmt::Debug;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub enum EffectKind {
     MageCurse,
@@ -18,6 +21,7 @@ pub enum EffectKind {
     Potion,
     Poison,
     Fire,
+    Unpaid,
 }
 
 #[enum_dispatch]
@@ -36,6 +40,7 @@ pub enum Effect {
     SpearEffect(SpearEffect),
     ItemEffect(ItemEffect),
     ToEndEffect(ToEndEffect),
+    TimedEffect(TimedEffect),
 }
 
 dyn_clone::clone_trait_object!(EffectTrait);
@@ -48,6 +53,10 @@ pub trait EffectTrait: DynClone + Debug + Send + Sync {
     fn on_battle_end(&mut self) -> bool {
         false
     }
+    /// Called when the world time passes, see `Unit::on_time`
+    fn on_time(&mut self, _time: Time) -> bool {
+        false
+    }
     fn tick(&mut self, unit: &mut Unit) -> bool {
         self.on_tick();
         true
@@ -547,3 +556,44 @@ impl EffectTrait for ToEndEffect {
         EffectKind::Bonus
     }
 }
+
+/// Effect that lasts for world time and is kept between battles, like potions and blessings
+#[derive(Copy, Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct TimedEffect {
+    pub info: EffectInfo,
+    /// World time the effect ends at
+    pub until: Time,
+    pub kind: EffectKind,
+    pub modify: ModifyUnitStats,
+}
+impl TimedEffect {
+    pub fn new(modify: ModifyUnitStats, kind: EffectKind, now: Time, duration: Time) -> Self {
+        Self {
+            info: EffectInfo { lifetime: i32::MAX },
+            until: now + duration,
+            kind,
+            modify,
+        }
+    }
+}
+impl EffectTrait for TimedEffect {
+    fn update_stats(&mut self, unit: &mut Unit) {
+        unit.modify += self.modify;
+    }
+    fn on_time(&mut self, time: Time) -> bool {
+        if time >= self.until {
+            self.info.lifetime = 0;
+        }
+        true
+    }
+    fn kill(&mut self, unit: &mut Unit) {
+        unit.modify -= self.modify;
+    }
+    fn is_dead(&self) -> bool {
+        self.info.lifetime < 1
+    }
+    fn get_kind(&self) -> EffectKind {
+        self.kind
+    }
+}
@@ -2,6 +2,8 @@ use crate::units::unitstats::ModifyUnitStats;
 
 use crate::{
     bonuses::bonus::Bonus,
+    effects::effect::{EffectKind, TimedEffect},
+    time::time::Time,
     units::unit::{MagicType, Unit},
 };
 use advini::{Ini, IniParseError};
@@ -40,9 +42,11 @@ pub struct ItemInfo {
     pub itemtype: ArtifactType,
     pub bonus: Option<Bonus>,
     pub modify: ModifyUnitStats,
+    /// How long the stats last after the item is drunk, see `Item::drink`
+    pub duration: Option<Time>,
 }
 pub static ITEMS: Lazy<Mutex<HashMap<usize, ItemInfo>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Item {
     pub index: usize,
@@ -62,6 +66,20 @@ impl Item {
     pub fn get_info(&self) -> ItemInfo {
         ITEMS.lock().unwrap().get(&self.index).unwrap().clone()
     }
+    /// Gives the unit the stats of the item for its duration from the world time `now`.
+    /// Only items with a duration, like potions, can be drunk
+    pub fn drink(&self, unit: &mut Unit, now: Time) -> bool {
+        let info = self.get_info();
+        let Some(duration) = info.duration else {
+            return false;
+        };
+        unit.add_effect(TimedEffect::new(
+            info.modify,
+            EffectKind::Potion,
+            now,
+            duration,
+        ))
+    }
     pub fn can_equip(&self, unit: &Unit) -> bool {
         let info = self.get_info();
         match info.itemtype {
@@ -10,6 +10,8 @@ pub mod network;
 pub mod parse;
 pub mod time;
 pub mod units;
+#[cfg(test)]
+mod test_helpers;
 
 #[repr(u32)]
 pub enum Menu {
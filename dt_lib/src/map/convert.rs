@@ -1,5 +1,5 @@
 use crate::{
-    battle::{control::{Control, Relations}, Army, ArmyStats, Troop}, items::{Item, ITEMS}, map::object::{BuildingVariant, Village}, mutrc::SendMut, time::time::Time, units::unit::{Unit, UNITS}
+    battle::{control::{Control, Relations}, rules::BATTLE_RULES, Army, ArmyStats, Troop}, items::{Item, ITEMS}, map::object::{BuildingVariant, Village}, mutrc::SendMut, time::time::Time, units::unit::{Unit, UNITS}
 };
 
 use super::{deco::*, map::*, object::{MapBuildingdata, Market, RecruitUnit, Recruitment}};
@@ -602,12 +602,14 @@ impl FromDtm for Army {
 		let pos = pos_from_dtm((army.x as usize, army.y as usize));
 		let active = army.activity.to_bool();
 		let control = Control::PC;
-		Army::new(troops.collect(),
+		let mut new_army = Army::new(troops.collect(),
 				  stats,
 				  inventory,
 				  pos,
 				  active,
-				  control)
+				  control);
+		new_army.group = army.group_type as usize;
+		new_army
 	}
 }
 impl FromDtm for MapBuildingdata {
@@ -639,7 +641,7 @@ impl FromDtm for MapBuildingdata {
 		);
 		let variant = match building.variant {
 			1 => BuildingVariant::Town,
-			2 => BuildingVariant::Village(Village { max_mana, max_gold }),
+			2 => BuildingVariant::Village(Village::new(max_gold, max_mana)),
 			3 => BuildingVariant::Castle,
 			4 => BuildingVariant::Fort,
 			5 => BuildingVariant::Tavern,
@@ -662,7 +664,7 @@ impl FromDtm for MapBuildingdata {
 				max_items
 			}.into()
 		} else { None };
-		let recruitment = Some(Recruitment::new(building.recruits.map(|x| RecruitUnit { unit: x.id as usize, count: x.amount as usize}).to_vec(), 1.));
+		let recruitment = Some(Recruitment::new(building.recruits.map(|x| RecruitUnit::new(x.id as usize, x.amount as usize)).to_vec(), 1.));
 		let mut garrison = vec![];
 		for unit in building.garrison_units {
 			let units = [UNITS.read().unwrap()[unit.id.min(100) as usize].clone()];
@@ -734,6 +736,7 @@ pub fn convert_dtm_map(mut data: MapData) -> GameMap {
 		tilemap,
 		decomap,
 		relations: FractionsRelations::default(),
+		battle_rules: BATTLE_RULES.clone(),
 		hitmap: TileMap::new((0..(size.0 * size.0)).map(|_| HitboxTile::default())),
 		buildings,
 		armys
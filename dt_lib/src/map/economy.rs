@@ -0,0 +1,375 @@
+use super::{map::GameMap, object::BuildingVariant};
+use crate::{
+    battle::army::Army,
+    bonuses::Bonus,
+    effects::effect::{EffectKind, TimedEffect},
+    parse::LOCALE,
+    time::time::{Data, Time},
+    units::unitstats::ModifyUnitStats,
+};
+use math_thingies::Percent;
+
+/// Days between refills of the units buildings hire, see `Recruitment::restock`
+pub const RECRUITMENT_DAYS: u64 = 7;
+/// Change of the attack of a troop left without pay, until it is paid or a day passes
+const UNPAID_ATTACK: Percent = Percent::const_new(-25);
+
+/// Money of one army on a payday, see `payday`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ledger {
+    pub army: usize,
+    pub time: Time,
+    /// Income of the buildings the army owns besides villages, see `MapBuildingdata::gold_income`
+    pub building_gold: u64,
+    pub building_mana: u64,
+    /// Income of a PC army, see `PC_ControlSetings::gold_income`
+    pub pc_gold: u64,
+    pub pc_mana: u64,
+    /// Gold paid to the troops
+    pub upkeep: u64,
+    /// Troops that got no pay this time and lost morale, see `UNPAID_ATTACK`
+    pub unpaid: usize,
+    /// Names of the troops that left the army after a second payday without pay
+    pub deserted: Vec<String>,
+}
+impl Ledger {
+    /// Gold the army got minus what it paid
+    pub fn balance(&self) -> i64 {
+        (self.building_gold + self.pc_gold) as i64 - self.upkeep as i64
+    }
+    /// Text telling the player about the payday
+    pub fn report(&self) -> String {
+        let locale = LOCALE.lock().unwrap();
+        let income = self.building_gold + self.pc_gold;
+        let mut lines = vec![locale
+            .get("payday_report")
+            .replacen("{}", &income.to_string(), 1)
+            .replacen("{}", &self.upkeep.to_string(), 1)];
+        if self.unpaid > 0 {
+            let unpaid = self.unpaid.to_string();
+            lines.push(locale.get("payday_unpaid").replace("{}", &unpaid));
+        }
+        if !self.deserted.is_empty() {
+            let deserted = self.deserted.join(", ");
+            lines.push(locale.get("payday_deserted").replace("{}", &deserted));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Runs what happens on the time boundaries passed since `from`:
+/// every 12 hours troops use bonuses like `ArmyMedic`, every day is a payday
+/// and markets restock, every `RECRUITMENT_DAYS` buildings get new units to hire.
+/// Inactive armies, like siege garrisons, are skipped.
+/// Hourly regeneration is done by `GameMap::pass_time`
+pub fn tick(gamemap: &mut GameMap, from: Time) -> Vec<Ledger> {
+    let half_days = gamemap.time.get_hours() / 12 - from.get_hours() / 12;
+    let days = gamemap.time.get_days() - from.get_days();
+    let weeks = gamemap.time.get_days() / RECRUITMENT_DAYS - from.get_days() / RECRUITMENT_DAYS;
+    for army in gamemap
+        .armys
+        .iter()
+        .filter(|army| !army.defeated && army.active)
+    {
+        for _ in 0..half_days {
+            on_12_hour(army);
+        }
+    }
+    if days > 0 {
+        restock_markets(gamemap);
+    }
+    if weeks > 0 {
+        restock_recruitment(gamemap);
+    }
+    let mut ledgers = Vec::new();
+    for _ in 0..days {
+        ledgers.extend(payday(gamemap));
+    }
+    ledgers
+}
+
+/// Every alive troop uses its bonus on the army, see `Bonus::on_12_hour`
+fn on_12_hour(army: &Army) {
+    let bonuses: Vec<Bonus> = army
+        .troops
+        .iter()
+        .map(|troop| troop.get())
+        .filter(|troop| !troop.is_dead())
+        .map(|troop| troop.unit.get_bonus())
+        .collect();
+    for bonus in bonuses {
+        bonus.on_12_hour(army);
+    }
+}
+
+/// Markets of the buildings refill their stock, see `Market::restock`
+fn restock_markets(gamemap: &mut GameMap) {
+    for building in &mut gamemap.buildings {
+        if let Some(market) = &mut building.market {
+            market.restock();
+        }
+    }
+}
+
+/// Recruitment of the buildings gets back the hired units, see `Recruitment::restock`
+fn restock_recruitment(gamemap: &mut GameMap) {
+    for building in &mut gamemap.buildings {
+        if let Some(recruitment) = &mut building.recruitment {
+            recruitment.restock();
+        }
+    }
+}
+
+/// Armies get the income of their buildings and PC settings, villages gather taxes.
+/// Then armies pay their troops.
+/// A troop left without pay loses morale (`Troop::was_payed`) and attacks worse until paid,
+/// if it is still unpaid on the next payday it deserts.
+/// Free troops cost nothing and the main troop never deserts. Inactive armies are skipped
+pub fn payday(gamemap: &mut GameMap) -> Vec<Ledger> {
+    let now = gamemap.time;
+    let mut ledgers: Vec<Ledger> = (0..gamemap.armys.len())
+        .map(|army| Ledger {
+            army,
+            time: gamemap.time,
+            ..Default::default()
+        })
+        .collect();
+    for building in &mut gamemap.buildings {
+        // Villages keep their taxes until an army collects them, see `GameMap::collect_taxes`
+        if let BuildingVariant::Village(village) = &mut building.variant {
+            village.accumulate(building.gold_income, building.mana_income);
+            continue;
+        }
+        let Some(owner) = building.owner.filter(|owner| *owner < gamemap.armys.len()) else {
+            continue;
+        };
+        ledgers[owner].building_gold += building.gold_income;
+        ledgers[owner].building_mana += building.mana_income;
+    }
+    for (army, ledger) in gamemap.armys.iter_mut().zip(&mut ledgers) {
+        if army.defeated || !army.active {
+            continue;
+        }
+        if let Some(settings) = &army.pc_settings {
+            ledger.pc_gold = settings.gold_income;
+            ledger.pc_mana = settings.mana_income;
+        }
+        army.stats.gold += ledger.building_gold + ledger.pc_gold;
+        army.stats.mana += ledger.building_mana + ledger.pc_mana;
+        let pays = !army
+            .pc_settings
+            .as_ref()
+            .is_some_and(|settings| settings.units_dont_have_money);
+        if pays {
+            pay_troops(army, ledger, now);
+        }
+    }
+    ledgers.retain(|ledger| {
+        let army = &gamemap.armys[ledger.army];
+        !army.defeated && army.active
+    });
+    ledgers
+}
+
+fn pay_troops(army: &mut Army, ledger: &mut Ledger, now: Time) {
+    let mut deserters = Vec::new();
+    for (index, troop) in army.troops.clone().iter().enumerate() {
+        let mut troop = troop.get();
+        if troop.is_dead() {
+            continue;
+        }
+        let cost = troop.on_pay(army);
+        if cost <= army.stats.gold {
+            army.stats.gold -= cost;
+            ledger.upkeep += cost;
+            troop.was_payed = true;
+            troop.unit.remove_effect_kind(EffectKind::Unpaid);
+        } else if troop.was_payed || troop.is_main {
+            troop.was_payed = false;
+            troop.unit.remove_effect_kind(EffectKind::Unpaid);
+            troop.unit.add_effect(unpaid_effect(now));
+            ledger.unpaid += 1;
+        } else {
+            deserters.push(index);
+        }
+    }
+    for index in deserters.into_iter().rev() {
+        let troop = army.troops.remove(index);
+        let troop = troop.get();
+        let name = troop.custom_name.as_ref().unwrap_or(&troop.unit.info.name);
+        ledger.deserted.push(name.clone());
+    }
+    army.recalc_army_hitmap();
+}
+
+fn unpaid_effect(now: Time) -> TimedEffect {
+    let mut modify = ModifyUnitStats::default();
+    modify.damage.hand.percent_add = Some(UNPAID_ATTACK);
+    modify.damage.ranged.percent_add = Some(UNPAID_ATTACK);
+    TimedEffect::new(modify, EffectKind::Unpaid, now, Time::new(Data::DAY as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::{control::PC_ControlSetings, troop::Troop},
+        effects::effect::EffectTrait,
+        map::object::{Market, Village},
+        parse::parse_items,
+        test_helpers::{army_of, building, knight},
+        time::time::Data,
+    };
+    use advini::Ini;
+
+    #[test]
+    fn paying_troops() {
+        let cost = knight().info.cost;
+        assert!(cost > 0);
+        let mut main = Troop::new(knight());
+        main.is_main = true;
+        let mut hired = Troop::new(knight());
+        hired.custom_name = Some("Иван".into());
+        let mut free = Troop::new(knight());
+        free.is_free = true;
+        let mut gamemap = GameMap::default();
+        gamemap.armys.push(army_of(vec![main, hired, free], cost));
+
+        // Only the main troop is paid, the hired one loses morale
+        let attack = knight().modified.damage.hand;
+        let ledger = payday(&mut gamemap).remove(0);
+        assert_eq!((ledger.upkeep, ledger.unpaid), (cost, 1));
+        assert!(ledger.deserted.is_empty());
+        {
+            let hired = gamemap.armys[0].troops[1].get();
+            assert!(!hired.was_payed);
+            assert_eq!(hired.unit.modified.damage.hand, attack - attack / 4);
+        }
+        // No gold again, so the hired troop deserts while the main one stays
+        let ledger = payday(&mut gamemap).remove(0);
+        assert_eq!((ledger.upkeep, ledger.unpaid), (0, 1));
+        assert_eq!(ledger.deserted, vec!["Иван".to_string()]);
+        assert_eq!(gamemap.armys[0].troops.len(), 2);
+        assert_eq!(gamemap.armys[0].hitmap.iter().flatten().count(), 2);
+        let unpaid = |army: &Army| {
+            army.troops
+                .iter()
+                .map(|troop| {
+                    let troop = troop.get();
+                    let effects = troop.unit.effects.iter();
+                    effects
+                        .filter(|effect| effect.get_kind() == EffectKind::Unpaid)
+                        .count()
+                })
+                .sum::<usize>()
+        };
+        assert_eq!(unpaid(&gamemap.armys[0]), 1);
+
+        gamemap.buildings.push(building(0, 2 * cost));
+        let ledgers = gamemap.pass_time(Data::DAY as u64);
+        assert_eq!(ledgers.len(), 1);
+        assert_eq!(ledgers[0].building_gold, 2 * cost);
+        assert_eq!(ledgers[0].building_mana, 5);
+        assert_eq!(ledgers[0].balance(), cost as i64);
+        let army = &gamemap.armys[0];
+        assert_eq!((army.stats.gold, army.stats.mana), (cost, 5));
+        assert!(army.troops.iter().all(|troop| troop.get().was_payed));
+        assert_eq!(unpaid(army), 0);
+        assert_eq!(army.troops[0].get().unit.modified.damage.hand, attack);
+        assert!(gamemap.pass_time(Data::DAY as u64 - 1).is_empty());
+
+        // Inactive armies neither get income nor pay
+        gamemap.armys[0].active = false;
+        assert!(payday(&mut gamemap).is_empty());
+        assert_eq!(gamemap.armys[0].stats.gold, cost);
+    }
+    #[test]
+    fn pc_income() {
+        let mut army = army_of(vec![Troop::new(knight())], 0);
+        army.pc_settings = Some(PC_ControlSetings {
+            gold_income: 100,
+            mana_income: 10,
+            units_dont_have_money: true,
+            ..Default::default()
+        });
+        let mut gamemap = GameMap::default();
+        gamemap.armys.push(army);
+        let ledger = payday(&mut gamemap).remove(0);
+        assert_eq!(
+            (ledger.pc_gold, ledger.pc_mana, ledger.upkeep),
+            (100, 10, 0)
+        );
+        assert_eq!(gamemap.armys[0].stats.gold, 100);
+        assert!(gamemap.armys[0].troops[0].get().was_payed);
+    }
+    #[test]
+    fn medics_every_12_hours() {
+        let mut medic = knight();
+        medic.bonus = Bonus::ArmyMedic;
+        let mut wounded = knight();
+        wounded.stats.hp = 40;
+        wounded.recalc();
+        let mut gamemap = GameMap::default();
+        let troops = vec![Troop::new(medic.clone()), Troop::new(wounded.clone())];
+        gamemap.armys.push(army_of(troops, 0));
+        // Inactive armies, like a siege garrison, do not use their bonuses
+        let troops = vec![Troop::new(medic), Troop::new(wounded)];
+        gamemap.armys.push(army_of(troops, 0));
+        gamemap.armys[1].active = false;
+        gamemap.pass_time(11 * Data::HOUR as u64);
+        assert_eq!(gamemap.armys[0].troops[1].get().unit.modified.hp, 40);
+        gamemap.pass_time(Data::HOUR as u64);
+        assert_eq!(gamemap.armys[0].troops[1].get().unit.modified.hp, 48);
+        assert_eq!(gamemap.armys[1].troops[1].get().unit.modified.hp, 40);
+    }
+    #[test]
+    fn village_taxes() {
+        let mut gamemap = GameMap::default();
+        gamemap.armys.push(army_of(vec![], 0));
+        gamemap.armys.push(army_of(vec![], 0));
+        let mut village = building(0, 40);
+        village.variant = BuildingVariant::Village(Village::new(100, 8));
+        gamemap.buildings.push(village);
+        for _ in 0..3 {
+            assert_eq!(payday(&mut gamemap)[0].building_gold, 0);
+        }
+        let BuildingVariant::Village(village) = &gamemap.buildings[0].variant else {
+            panic!("Not a village")
+        };
+        assert_eq!((village.gold, village.mana), (100, 8));
+        let (saved, _) = Village::eat(village.vomit().chars()).unwrap();
+        assert_eq!((saved.max_gold, saved.gold, saved.mana), (100, 100, 8));
+        let (old, _) = Village::eat("100,8".chars()).unwrap();
+        assert_eq!((old.max_mana, old.gold), (8, 0));
+
+        // Only the owner standing in the village collects
+        assert_eq!(gamemap.collect_taxes(0), None);
+        gamemap.armys[1].building = Some(0);
+        assert_eq!(gamemap.collect_taxes(1), None);
+        gamemap.armys[0].building = Some(0);
+        assert_eq!(gamemap.collect_taxes(0), Some((100, 8)));
+        assert_eq!(gamemap.armys[0].stats.gold, 100);
+        assert_eq!(gamemap.collect_taxes(0), Some((0, 0)));
+    }
+    #[test]
+    fn restocking_markets() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let mut gamemap = GameMap::default();
+        gamemap.armys.push(army_of(vec![], 0));
+        let mut town = building(0, 0);
+        town.market = Some(Market::new((200, 300), vec![], 3));
+        gamemap.buildings.push(town);
+        gamemap.armys[0].building = Some(0);
+        gamemap.pass_time(Data::DAY as u64 - 1);
+        assert!(gamemap.market(0).unwrap().0.items.is_empty());
+        gamemap.pass_time(1);
+        let (market, _) = gamemap.market(0).unwrap();
+        assert_eq!(market.items.len(), 3);
+        assert!(market
+            .items
+            .iter()
+            .all(|item| (200..=300).contains(&item.get_info().cost) && item.get_info().sells));
+        gamemap.armys[0].building = None;
+        assert!(gamemap.market(0).is_none());
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    battle::troop::Troop, items::Item, map::map::GameMap, mutrc::SendMut, time::time::Time, units::unit::{Unit, UnitPos}
+    battle::troop::Troop, effects::effect::{EffectKind, TimedEffect}, items::{Item, ITEMS}, map::map::GameMap, mutrc::SendMut, time::time::Time, units::unit::{Unit, UnitPos}
 };
 use advini::{Ini, IniParseError, Section, SectionError, Sections, SEPARATOR};
 use serde;
@@ -227,6 +227,9 @@ pub struct EventResult {
     pub remove_units: Option<Vec<usize>>,
     #[default_value = "None"]
     pub change_personality: Option<usize>, // Changes player-controlled army
+    /// Every troop of the army gets the stats of the item (index of all game items) for the time
+    #[default_value = "None"]
+    pub blessing: Option<(usize, Time)>,
 }
 
 #[derive(Clone, Debug, Default, Sections, serde::Serialize, serde::Deserialize)]
@@ -433,10 +436,25 @@ pub fn execute_event_as_player(
                         was_payed: true,
                         is_main: false,
                         pos: UnitPos::from_index(0),
+                        battle_stats: Default::default(),
                     }))
                     .ok();
                 });
             }
+            let blessing = result.blessing.and_then(|(item, duration)| {
+                let modify = ITEMS.lock().unwrap().get(&item)?.modify;
+                Some(TimedEffect::new(
+                    modify,
+                    EffectKind::Bonus,
+                    gamemap.time,
+                    duration,
+                ))
+            });
+            if let Some(blessing) = blessing {
+                for troop in &army.troops {
+                    troop.get().unit.add_effect(blessing);
+                }
+            }
         }
 
         let mut res = Vec::new();
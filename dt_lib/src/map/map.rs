@@ -1,12 +1,19 @@
 use std::ops::{Index, IndexMut};
 
 use super::{
-    object::{MapBuildingdata, ObjectInfo},
+    economy::{self, Ledger},
+    object::{BuildingVariant, MapBuildingdata, Market, ObjectInfo, RecruitError},
     tile::*,
 };
 use crate::{
-    battle::{army::Army, control::Relations},
-    time::time::Time,
+    battle::{
+        army::Army,
+        control::{Relations, ALLIED_ATTITUDE, ALLY_GROUP, ENEMY_GROUP, NEIGHBOUR_GROUP},
+        rules::{BattleRules, BATTLE_RULES},
+        terrain::terrain_of,
+    },
+    time::time::{Data, Time},
+    units::unit::Unit,
 };
 use advini::{Ini, IniParseError, Section, SectionError, Sections};
 use alkahest::alkahest;
@@ -140,6 +147,15 @@ pub struct StartStats {
     #[alias([start_time])]
     pub time: Time,
 }
+/// Building that changed its owner, see `GameMap::transfer_building`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capture {
+    pub building: usize,
+    pub army: usize,
+    pub old_owner: Option<usize>,
+    /// Events of the building to run for the new owner, see `MapBuildingdata::events`
+    pub events: Vec<usize>,
+}
 #[derive(Clone, Debug, Sections)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct GameMap {
@@ -159,6 +175,9 @@ pub struct GameMap {
     pub armys: Vec<Army>,
     #[inline_parsing]
     pub relations: FractionsRelations,
+    /// Rules for battles on this map
+    #[unused]
+    pub battle_rules: BattleRules,
     #[unused]
     pub pause: bool,
 }
@@ -173,6 +192,7 @@ impl Default for GameMap {
             buildings: Vec::new(),
             armys: Vec::new(),
             relations: Default::default(),
+            battle_rules: BATTLE_RULES.clone(),
             pause: false,
         }
     }
@@ -214,7 +234,290 @@ impl GameMap {
             if !army.active || army.defeated {
                 continue;
             }
-            self.hitmap[army.pos].building = Some(i);
+            self.hitmap[army.pos].army = Some(i);
+        }
+    }
+    /// Free tile next to the army it can retreat to
+    pub fn retreat_tile(&self, army: usize) -> Option<(usize, usize)> {
+        let pos = self.armys.get(army)?.pos;
+        let size = self.hitmap.size;
+        (-1i64..=1)
+            .flat_map(|x| (-1i64..=1).map(move |y| (x, y)))
+            .filter(|shift| *shift != (0, 0))
+            .map(|(x, y)| (pos.0 as i64 + x, pos.1 as i64 + y))
+            .filter(|(x, y)| (0..size as i64).contains(x) && (0..size as i64).contains(y))
+            .map(|(x, y)| (x as usize, y as usize))
+            .find(|tile| {
+                let hitbox = &self.hitmap[*tile];
+                hitbox.passable()
+                    && hitbox.building.is_none()
+                    && !hitbox.need_transport
+                    && TILES[self.tilemap[*tile]].walkspeed > 0
+            })
+    }
+    /// Moves the army to a free tile next to it, returns the new position
+    pub fn retreat_army(&mut self, army: usize) -> Option<(usize, usize)> {
+        let to = self.retreat_tile(army)?;
+        let army = &mut self.armys[army];
+        army.pos = to;
+        army.path.clear();
+        army.building = None;
+        self.recalc_armies_hitboxes();
+        Some(to)
+    }
+    /// Relations of the army: its own PC settings or the ones of its fraction
+    pub fn army_relations(&self, army: usize) -> Relations {
+        let army = &self.armys[army];
+        if let Some(settings) = &army.pc_settings {
+            return settings.relations.clone();
+        }
+        match army.group {
+            ALLY_GROUP => self.relations.ally.clone(),
+            NEIGHBOUR_GROUP => self.relations.neighbour.clone(),
+            ENEMY_GROUP => self.relations.enemy.clone(),
+            _ => Relations::default(),
+        }
+    }
+    /// Armies are allies when both like each other's group
+    pub fn are_allies(&self, army1: usize, army2: usize) -> bool {
+        let (group1, group2) = (self.armys[army1].group, self.armys[army2].group);
+        self.army_relations(army1).towards(group2) < ALLIED_ATTITUDE
+            && self.army_relations(army2).towards(group1) < ALLIED_ATTITUDE
+    }
+    /// Sides of a battle between two armies: each leader first, then its allies
+    /// standing next to one of the leaders and hostile to the other one
+    pub fn battle_sides(&self, army1: usize, army2: usize) -> [Vec<usize>; 2] {
+        let mut sides = [vec![army1], vec![army2]];
+        let near = |army: &Army, leader: usize| {
+            let pos = self.armys[leader].pos;
+            pos.0.abs_diff(army.pos.0) <= 1 && pos.1.abs_diff(army.pos.1) <= 1
+        };
+        for (index, army) in self.armys.iter().enumerate() {
+            if index == army1
+                || index == army2
+                || !army.active
+                || army.defeated
+                || !(near(army, army1) || near(army, army2))
+            {
+                continue;
+            }
+            let (ally1, ally2) = (self.are_allies(index, army1), self.are_allies(index, army2));
+            if ally1 && !ally2 {
+                sides[0].push(index);
+            } else if ally2 && !ally1 {
+                sides[1].push(index);
+            }
+        }
+        sides
+    }
+    /// Terrain of a battle on the tile, see `terrain_of`
+    pub fn battle_terrain(&self, pos: (usize, usize)) -> Option<usize> {
+        let building = self.hitmap[pos]
+            .building
+            .and_then(|building| self.buildings.get(building));
+        terrain_of(self.tilemap[pos], building.map(|data| &data.variant))
+    }
+    /// Buildings the army owns
+    pub fn owned_buildings(&self, army: usize) -> Vec<usize> {
+        self.buildings
+            .iter()
+            .enumerate()
+            .filter(|(_, data)| data.owner == Some(army))
+            .map(|(building, _)| building)
+            .collect()
+    }
+    /// The building has no owner or it belongs to an army that is not an ally of this one
+    pub fn can_capture(&self, building: usize, army: usize) -> bool {
+        let Some(data) = self.buildings.get(building) else {
+            return false;
+        };
+        match data.owner {
+            None => true,
+            Some(owner) if owner >= self.armys.len() => true,
+            Some(owner) => owner != army && !self.are_allies(owner, army),
+        }
+    }
+    /// The army takes the building it stands in if the building has no garrison left,
+    /// see `GameMap::can_capture`. Guarded buildings are taken by `end_siege`
+    pub fn capture(&mut self, army: usize) -> Option<Capture> {
+        let building = self.armys.get(army)?.building?;
+        if !self.can_capture(building, army) || !self.buildings[building].garrison.is_empty() {
+            return None;
         }
+        Some(self.transfer_building(building, army))
+    }
+    /// Makes the army the owner of the building, so the income and the owner's price
+    /// of recruitment go to it and the building takes its group and relations.
+    /// PC armies of the old owner's group stop being friendly to the new owner's group
+    pub fn transfer_building(&mut self, building: usize, army: usize) -> Capture {
+        let relations = self.army_relations(army);
+        let group = self.armys[army].group;
+        let data = &mut self.buildings[building];
+        let old_owner = data.owner.replace(army);
+        data.owner_name = self.armys[army].stats.army_name.clone();
+        data.group = group;
+        data.relations = relations;
+        let events = data.events.clone();
+        let old_group = old_owner
+            .and_then(|owner| self.armys.get(owner))
+            .map(|owner| owner.group)
+            .filter(|old_group| *old_group != group);
+        if let Some(old_group) = old_group {
+            for other in &mut self.armys {
+                let Some(settings) = &mut other.pc_settings else {
+                    continue;
+                };
+                if other.group == old_group {
+                    let attitude = settings.relations.towards_mut(group);
+                    *attitude = (*attitude).max(ALLIED_ATTITUDE);
+                }
+            }
+        }
+        Capture {
+            building,
+            army,
+            old_owner,
+            events,
+        }
+    }
+    /// The army takes the taxes of the village it stands in if it owns the village.
+    /// Returns the gold and the mana collected
+    pub fn collect_taxes(&mut self, army: usize) -> Option<(u64, u64)> {
+        let building = self.armys.get(army)?.building?;
+        let data = self.buildings.get_mut(building)?;
+        let BuildingVariant::Village(village) = &mut data.variant else {
+            return None;
+        };
+        if data.owner != Some(army) {
+            return None;
+        }
+        let (gold, mana) = village.collect();
+        let stats = &mut self.armys[army].stats;
+        stats.gold += gold;
+        stats.mana += mana;
+        Some((gold, mana))
+    }
+    /// Market of the building the army stands in, with the army to trade for
+    pub fn market(&mut self, army: usize) -> Option<(&mut Market, &mut Army)> {
+        let building = self.armys.get(army)?.building?;
+        let market = self.buildings.get_mut(building)?.market.as_mut()?;
+        Some((market, &mut self.armys[army]))
+    }
+    /// The army hires the unit in the building it stands in.
+    /// The price depends on the attitude of the building to the army, owners pay the least.
+    /// Returns the gold paid, see `Recruitment::buy`
+    pub fn recruit(
+        &mut self,
+        army: usize,
+        unit_num: usize,
+        units: &[Unit],
+    ) -> Result<u64, RecruitError> {
+        let Some(buyer) = self.armys.get_mut(army) else {
+            return Err(RecruitError::NoRecruitment);
+        };
+        let building = buyer
+            .building
+            .and_then(|building| self.buildings.get_mut(building))
+            .ok_or(RecruitError::NoRecruitment)?;
+        let attitude = if building.owner == Some(army) {
+            0
+        } else {
+            building.relations.towards(buyer.group)
+        };
+        let recruitment = building
+            .recruitment
+            .as_mut()
+            .ok_or(RecruitError::NoRecruitment)?;
+        recruitment.buy(buyer, unit_num, units, attitude)
+    }
+    /// Moves the world time forward, troops regenerate once for every full hour passed
+    /// and their effects that expired by the new time end.
+    /// Returns the ledgers of paydays on the way, see `economy::tick`
+    pub fn pass_time(&mut self, minutes: u64) -> Vec<Ledger> {
+        let from = self.time;
+        let hour = Data::HOUR as u64;
+        let hours = (self.time.minutes + minutes) / hour - self.time.minutes / hour;
+        self.time.minutes += minutes;
+        for army in &mut self.armys {
+            for _ in 0..hours {
+                army.regenerate();
+            }
+            for troop in &army.troops {
+                troop.get().unit.on_time(self.time);
+            }
+        }
+        economy::tick(self, from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::control::{PC_ControlSetings, PLAYER_GROUP},
+        test_helpers::{army_of, building, knight},
+    };
+
+    #[test]
+    fn capturing_buildings() {
+        let pc = |player| {
+            Some(PC_ControlSetings {
+                relations: Relations {
+                    player,
+                    ally: 0,
+                    neighbour: 0,
+                    enemy: 0,
+                },
+                ..Default::default()
+            })
+        };
+        let mut gamemap = GameMap::default();
+        let mut owner = army_of(vec![], 0);
+        owner.pc_settings = pc(255);
+        let mut raider = army_of(vec![], 0);
+        raider.group = PLAYER_GROUP;
+        raider.stats.army_name = "Налётчики".into();
+        let mut friend = army_of(vec![], 0);
+        friend.pc_settings = pc(0);
+        gamemap.armys.extend([owner, raider, friend]);
+        let mut castle = building(0, 50);
+        castle.events = vec![3];
+        castle.garrison.push(knight());
+        gamemap.buildings.push(castle);
+        gamemap.buildings.push(building(0, 0));
+
+        // Allies do not take buildings, nobody takes a guarded one
+        assert_eq!(gamemap.capture(1), None);
+        gamemap.armys[1].building = Some(0);
+        gamemap.armys[2].building = Some(0);
+        assert!(!gamemap.can_capture(0, 2));
+        assert_eq!(gamemap.capture(2), None);
+        assert_eq!(gamemap.capture(1), None);
+        gamemap.buildings[0].garrison.clear();
+        let capture = Capture {
+            building: 0,
+            army: 1,
+            old_owner: Some(0),
+            events: vec![3],
+        };
+        assert_eq!(gamemap.capture(1), Some(capture));
+        assert_eq!(gamemap.capture(1), None);
+
+        let castle = &gamemap.buildings[0];
+        assert_eq!(castle.owner_name, "Налётчики");
+        assert_eq!(castle.group, PLAYER_GROUP);
+        assert_eq!(castle.relations.towards(ENEMY_GROUP), 255);
+        let attitude = |army: usize| {
+            let settings = gamemap.armys[army].pc_settings.as_ref().unwrap();
+            settings.relations.towards(PLAYER_GROUP)
+        };
+        assert_eq!((attitude(0), attitude(2)), (255, ALLIED_ATTITUDE));
+        assert_eq!(gamemap.owned_buildings(0), vec![1]);
+        assert_eq!(gamemap.owned_buildings(1), vec![0]);
+        let ledgers = economy::payday(&mut gamemap);
+        assert_eq!(
+            (ledgers[0].building_gold, ledgers[1].building_gold),
+            (0, 50)
+        );
     }
 }
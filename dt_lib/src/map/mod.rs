@@ -1,5 +1,6 @@
 pub mod convert;
 pub mod deco;
+pub mod economy;
 pub mod event;
 pub mod map;
 pub mod object;
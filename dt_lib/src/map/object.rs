@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 
 use crate::{
-    battle::{army::Army, control::Relations, troop::Troop},
+    battle::{army::Army, control::Relations, rules::BATTLE_SETTINGS, troop::Troop},
     items::{item::ITEMS, Item},
     units::unit::Unit,
 };
 use advini::*;
 use alkahest::alkahest;
+use math_thingies::Percent;
 use num_enum::FromPrimitive;
 use rand::{seq::SliceRandom, thread_rng};
 
@@ -32,15 +33,59 @@ pub struct ObjectInfo {
 pub struct Village {
     pub max_gold: u64,
     pub max_mana: u64,
+    /// Taxes waiting for the owner, see `Village::collect`
+    pub gold: u64,
+    pub mana: u64,
 }
 impl Ini for Village {
     fn eat(chars: std::str::Chars) -> Result<(Self, std::str::Chars), IniParseError> {
         let (max_gold, chars) = u64::eat(chars)?;
         let (max_mana, chars) = u64::eat(chars)?;
-        Ok((Self { max_gold, max_mana }, chars))
+        // Saved maps also keep the taxes collected so far
+        let ((gold, mana), chars) = match <(u64, u64)>::eat(chars.clone()) {
+            Ok(treasury) => treasury,
+            Err(_) => ((0, 0), chars),
+        };
+        Ok((
+            Self {
+                max_gold,
+                max_mana,
+                gold,
+                mana,
+            },
+            chars,
+        ))
     }
     fn vomit(&self) -> String {
-        [self.max_gold.vomit(), self.max_mana.vomit()].join(",")
+        [
+            self.max_gold.vomit(),
+            self.max_mana.vomit(),
+            self.gold.vomit(),
+            self.mana.vomit(),
+        ]
+        .join(",")
+    }
+}
+impl Village {
+    pub fn new(max_gold: u64, max_mana: u64) -> Self {
+        Self {
+            max_gold,
+            max_mana,
+            gold: 0,
+            mana: 0,
+        }
+    }
+    /// Adds taxes, the treasury never grows over `max_gold` and `max_mana`
+    pub fn accumulate(&mut self, gold: u64, mana: u64) {
+        self.gold = (self.gold + gold).min(self.max_gold);
+        self.mana = (self.mana + mana).min(self.max_mana);
+    }
+    /// Empties the treasury, returns the gold and the mana taken
+    pub fn collect(&mut self) -> (u64, u64) {
+        (
+            std::mem::take(&mut self.gold),
+            std::mem::take(&mut self.mana),
+        )
     }
 }
 #[derive(Clone, Debug, Ini)]
@@ -61,6 +106,27 @@ pub enum BuildingVariant {
     StoneBridge,
     WoodenBridge,
 }
+impl BuildingVariant {
+    /// Name of the variant without its data, as written in data files
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildingVariant::Town => "Town",
+            BuildingVariant::Village(_) => "Village",
+            BuildingVariant::Castle => "Castle",
+            BuildingVariant::Fort => "Fort",
+            BuildingVariant::Tavern => "Tavern",
+            BuildingVariant::Market => "Market",
+            BuildingVariant::Church => "Church",
+            BuildingVariant::Forge => "Forge",
+            BuildingVariant::Verf => "Verf",
+            BuildingVariant::Altar => "Altar",
+            BuildingVariant::Mine => "Mine",
+            BuildingVariant::Ruins(_) => "Ruins",
+            BuildingVariant::StoneBridge => "StoneBridge",
+            BuildingVariant::WoodenBridge => "WoodenBridge",
+        }
+    }
+}
 #[derive(Clone, Debug, Sections)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct MapBuildingdata {
@@ -103,7 +169,6 @@ pub struct MapBuildingdata {
     #[default_value = "0usize"]
     pub group: usize,
 }
-const RECRUIT_COST: f64 = 2.0;
 #[derive(Clone, Debug, Sections)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Market {
@@ -111,49 +176,120 @@ pub struct Market {
     pub items: Vec<Item>,
     pub max_items: usize,
 }
+/// Share of the item cost the market pays for it, see `Market::get_sell_price`
+pub const SELL_RATIO: Percent = Percent::const_new(50);
+
+/// Deal made on the market, for the UI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trade {
+    Bought { item: Item, gold: u64 },
+    Sold { item: Item, gold: u64 },
+}
+/// Why the market refused the deal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TradeError {
+    /// There is no item at the index
+    NoItem,
+    /// The item can not be traded, see `ItemInfo::sells`
+    NotForSale,
+    NotEnoughGold,
+    /// The market already has `Market::max_items` and takes no more
+    MarketFull,
+}
 impl Market {
-    fn new(itemcost_range: (u64, u64), items: Vec<Item>, max_items: usize) -> Self {
+    pub fn new(itemcost_range: (u64, u64), items: Vec<Item>, max_items: usize) -> Self {
         Self {
             itemcost_range,
             items,
             max_items,
         }
     }
-    fn update(&mut self) {
-        for _ in self.max_items - self.items.len()..0 {
-            let items = ITEMS.lock().unwrap();
-            let nice_items = items
-                .iter()
-                .filter(|(_, item)| {
-                    self.itemcost_range.0 <= item.cost && item.cost <= self.itemcost_range.1
-                })
-                .collect::<Vec<_>>();
-            self.items.append(
-                &mut nice_items
-                    .choose_multiple(&mut thread_rng(), self.max_items)
-                    .map(|(index, _)| Item { index: **index })
-                    .collect(),
-            );
+    /// Fills the stock up to `max_items` with random items that cost within `itemcost_range`
+    pub fn restock(&mut self) {
+        let missing = self.max_items.saturating_sub(self.items.len());
+        if missing == 0 {
+            return;
         }
+        let items = ITEMS.lock().unwrap();
+        let nice_items = items
+            .iter()
+            .filter(|(_, item)| {
+                item.sells
+                    && self.itemcost_range.0 <= item.cost
+                    && item.cost <= self.itemcost_range.1
+            })
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        self.items.extend(
+            nice_items
+                .choose_multiple(&mut thread_rng(), missing)
+                .map(|index| Item { index: *index }),
+        );
+    }
+    pub fn buy(&mut self, buyer: &mut Army, item_num: usize) -> Result<Trade, TradeError> {
+        let gold = self.can_buy(buyer, item_num)?;
+        buyer.stats.gold -= gold;
+        let item = self.items.remove(item_num);
+        buyer.add_item(item);
+        Ok(Trade::Bought { item, gold })
     }
-    fn buy(&mut self, buyer: &mut Army, item_num: usize) {
-        if self.can_buy(buyer, item_num) {
-            buyer.stats.gold = buyer
-                .stats
-                .gold
-                .saturating_sub(self.get_item_cost(item_num));
-            buyer.add_item(self.items.remove(item_num));
+    /// Price of the item for the buyer if they can buy it
+    pub fn can_buy(&self, buyer: &Army, item_num: usize) -> Result<u64, TradeError> {
+        let item = self.items.get(item_num).ok_or(TradeError::NoItem)?;
+        if !item.get_info().sells {
+            return Err(TradeError::NotForSale);
+        }
+        let cost = self.get_item_cost(item_num, buyer);
+        if buyer.stats.gold < cost {
+            return Err(TradeError::NotEnoughGold);
         }
+        Ok(cost)
+    }
+    /// Price of the item for the buyer, see `Army::trade_discount`
+    pub fn get_item_cost(&self, item_num: usize, buyer: &Army) -> u64 {
+        let cost = self.items[item_num].get_info().cost;
+        cost - buyer.trade_discount().calc(cost)
+    }
+    /// Gold the seller gets for the item: `SELL_RATIO` of its cost,
+    /// raised by `Army::trade_discount` if the army has a merchant
+    pub fn get_sell_price(&self, item: Item, seller: &Army) -> u64 {
+        (SELL_RATIO + seller.trade_discount()).calc(item.get_info().cost)
+    }
+    /// The army sells an item from its inventory, the market puts it on sale
+    pub fn sell(&mut self, seller: &mut Army, item_num: usize) -> Result<Trade, TradeError> {
+        let item = *seller.inventory.get(item_num).ok_or(TradeError::NoItem)?;
+        let gold = self.sell_price(item, seller)?;
+        seller.inventory.remove(item_num);
+        Ok(self.sold(seller, item, gold))
     }
-    fn can_buy(&self, buyer: &Army, item_num: usize) -> bool {
-        if self.items[item_num].get_info().sells
-        {
-            return buyer.stats.gold >= self.get_item_cost(item_num);
+    /// The army sells an item a troop has in the slot, see `Unit::remove_item`
+    pub fn sell_unit_item(
+        &mut self,
+        seller: &mut Army,
+        troop: usize,
+        slot: usize,
+    ) -> Result<Trade, TradeError> {
+        let troop = seller.troops.get(troop).ok_or(TradeError::NoItem)?.clone();
+        let Some(Some(item)) = troop.get().unit.inventory.items.get(slot).copied() else {
+            return Err(TradeError::NoItem);
+        };
+        let gold = self.sell_price(item, seller)?;
+        troop.get().unit.remove_item(slot);
+        Ok(self.sold(seller, item, gold))
+    }
+    fn sell_price(&self, item: Item, seller: &Army) -> Result<u64, TradeError> {
+        if !item.get_info().sells {
+            return Err(TradeError::NotForSale);
+        }
+        if self.items.len() >= self.max_items {
+            return Err(TradeError::MarketFull);
         }
-        false
+        Ok(self.get_sell_price(item, seller))
     }
-    fn get_item_cost(&self, item_num: usize) -> u64 {
-        self.items[item_num].get_info().cost
+    fn sold(&mut self, seller: &mut Army, item: Item, gold: u64) -> Trade {
+        seller.stats.gold += gold;
+        self.items.push(item);
+        Trade::Sold { item, gold }
     }
 }
 #[derive(Clone, Debug)]
@@ -173,40 +309,248 @@ impl Ini for RecruitUnit {
     }
 }
 impl RecruitUnit {
-    fn new(unit: usize, count: usize) -> Self {
+    pub fn new(unit: usize, count: usize) -> Self {
         Self { unit, count }
     }
 }
+/// Why the unit can not be hired, see `Recruitment::can_buy`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecruitError {
+    /// The army is not in a building that hires units
+    NoRecruitment,
+    /// There is no unit at the index
+    NoUnit,
+    /// All the units of the kind are hired until the stock refills
+    NoStock,
+    NoGold,
+    /// There is no room for the unit in the army, see `Army::can_fit`
+    ArmyFull,
+}
 #[derive(Clone, Debug, Sections)]
 #[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
 pub struct Recruitment {
     pub units: Vec<RecruitUnit>,
     pub cost_modify: f64,
+    /// Counts of `units` the stock refills to, see `Recruitment::restock`
+    #[default_value = "Vec::new()"]
+    pub max_counts: Vec<usize>,
 }
 impl Recruitment {
     pub fn new(units: Vec<RecruitUnit>, cost_modify: f64) -> Self {
-        Self { units, cost_modify }
-    }
-    pub fn buy(&mut self, buyer: &mut Army, unit_num: usize, units: &Vec<Unit>) -> Result<(), ()> {
-        if self.can_buy(buyer, unit_num, units) {
-            buyer.add_troop(
-                Troop {
-                    unit: units[self.units[unit_num].unit].clone(),
-                    ..Troop::empty()
-                }
-                .into(),
-            )?;
-            self.units[unit_num].count -= 1;
-            buyer.stats.gold -= units[self.units[unit_num].unit].info.cost_hire;
+        let max_counts = units.iter().map(|unit| unit.count).collect();
+        Self {
+            units,
+            cost_modify,
+            max_counts,
         }
-        Err(())
-    }
-    pub fn can_buy(&self, buyer: &Army, unit_num: usize, units: &Vec<Unit>) -> bool {
-        let info = &self
-            .units
-            .get(unit_num)
-            .expect("Trying to get unit at unknown index");
-        buyer.stats.gold >= units[info.unit].info.cost_hire && info.count > 0
-        //* (RECRUIT_COST * self.cost_modify)) as u64;
+    }
+    /// Hires the unit into the army and returns the gold paid.
+    /// PC armies get the troop with `PC_ControlSetings::xp_add` experience
+    pub fn buy(
+        &mut self,
+        buyer: &mut Army,
+        unit_num: usize,
+        units: &[Unit],
+        attitude: u8,
+    ) -> Result<u64, RecruitError> {
+        let cost = self.can_buy(buyer, unit_num, units, attitude)?;
+        let mut troop = Troop::new(units[self.units[unit_num].unit].clone());
+        if let Some(settings) = &buyer.pc_settings {
+            troop.unit.add_xp(settings.xp_add);
+        }
+        buyer
+            .add_troop(troop.into())
+            .map_err(|_| RecruitError::ArmyFull)?;
+        self.units[unit_num].count -= 1;
+        buyer.stats.gold -= cost;
+        Ok(cost)
+    }
+    /// Price of the unit for the buyer if they can hire it
+    pub fn can_buy(
+        &self,
+        buyer: &Army,
+        unit_num: usize,
+        units: &[Unit],
+        attitude: u8,
+    ) -> Result<u64, RecruitError> {
+        let info = self.units.get(unit_num).ok_or(RecruitError::NoUnit)?;
+        let unit = units.get(info.unit).ok_or(RecruitError::NoUnit)?;
+        if info.count == 0 {
+            return Err(RecruitError::NoStock);
+        }
+        let cost = self.cost(unit, attitude);
+        if buyer.stats.gold < cost {
+            return Err(RecruitError::NoGold);
+        }
+        if !buyer.can_fit(unit) {
+            return Err(RecruitError::ArmyFull);
+        }
+        Ok(cost)
+    }
+    /// Hire cost of the unit here, see `BattleSettings::recruit_cost`.
+    /// The building asks more the worse its attitude to the buyer is, up to twice the price
+    pub fn cost(&self, unit: &Unit, attitude: u8) -> u64 {
+        let cost = unit.info.cost_hire as f64 * BATTLE_SETTINGS.recruit_cost * self.cost_modify;
+        (cost * (1. + attitude as f64 / u8::MAX as f64)) as u64
+    }
+    /// Brings back the units hired since the last refill
+    pub fn restock(&mut self) {
+        // Maps saved without the refill counts keep the stock they were loaded with
+        if self.max_counts.len() != self.units.len() {
+            self.max_counts = self.units.iter().map(|unit| unit.count).collect();
+        }
+        for (unit, max_count) in self.units.iter_mut().zip(&self.max_counts) {
+            unit.count = unit.count.max(*max_count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::control::PC_ControlSetings,
+        bonuses::Bonus,
+        map::{economy::RECRUITMENT_DAYS, map::GameMap},
+        parse::parse_items,
+        test_helpers::{army_of, building, knight, unit_index, TEST_UNITS},
+        time::time::Data,
+    };
+
+    #[test]
+    fn trading() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let (sword, necronomicon, potion) =
+            (Item { index: 1 }, Item { index: 89 }, Item { index: 95 });
+        let mut market = Market::new((0, 1000), vec![sword, necronomicon], 3);
+        let mut troop = knight();
+        troop.inventory.items = vec![None];
+        assert!(troop.add_item(potion, 0));
+        let mut army = army_of(vec![Troop::new(troop)], 200);
+        assert_eq!(market.buy(&mut army, 0), Err(TradeError::NotEnoughGold));
+        assert_eq!(market.buy(&mut army, 1), Err(TradeError::NotForSale));
+        assert_eq!(market.buy(&mut army, 2), Err(TradeError::NoItem));
+
+        // The potion sells for half of its cost and goes on sale
+        let sold = market.sell_unit_item(&mut army, 0, 0);
+        assert_eq!(
+            sold,
+            Ok(Trade::Sold {
+                item: potion,
+                gold: 125
+            })
+        );
+        assert_eq!(army.troops[0].get().unit.inventory.items[0], None);
+        assert_eq!(
+            market.sell_unit_item(&mut army, 0, 0),
+            Err(TradeError::NoItem)
+        );
+        let bought = market.buy(&mut army, 0);
+        assert_eq!(
+            bought,
+            Ok(Trade::Bought {
+                item: sword,
+                gold: 260
+            })
+        );
+        assert_eq!(army.stats.gold, 65);
+        assert_eq!(market.items, vec![necronomicon, potion]);
+
+        // A merchant buys cheaper and sells dearer
+        let mut merchant = knight();
+        merchant.bonus = Bonus::Merchant;
+        army.add_troop(Troop::new(merchant).into()).unwrap();
+        let sold = market.sell(&mut army, 0);
+        assert_eq!(
+            sold,
+            Ok(Trade::Sold {
+                item: sword,
+                gold: 195
+            })
+        );
+        assert_eq!(army.stats.gold, 260);
+        assert!(army.inventory.is_empty());
+        assert_eq!(market.sell(&mut army, 0), Err(TradeError::NoItem));
+        assert_eq!(market.can_buy(&army, 1), Ok(188));
+
+        // A full market buys nothing
+        army.add_item(potion);
+        assert_eq!(market.sell(&mut army, 0), Err(TradeError::MarketFull));
+        assert_eq!(army.inventory, vec![potion]);
+        assert_eq!(army.stats.gold, 260);
+        assert_eq!(market.items.len(), 3);
+    }
+    #[test]
+    fn recruiting() {
+        let units = &*TEST_UNITS;
+        let knight = unit_index("Рыцарь");
+        let cost = units[knight].info.cost_hire;
+        let mut gamemap = GameMap::default();
+        gamemap.armys.push(army_of(vec![], 3 * cost));
+        let mut hostile = army_of(vec![], 3 * cost);
+        hostile.pc_settings = Some(PC_ControlSetings {
+            xp_add: 30,
+            ..Default::default()
+        });
+        gamemap.armys.push(hostile);
+        let mut barracks = building(0, 0);
+        barracks.relations.enemy = 255;
+        barracks.recruitment = Some(Recruitment::new(vec![RecruitUnit::new(knight, 2)], 2.));
+        gamemap.buildings.push(barracks);
+        assert_eq!(
+            gamemap.recruit(0, 0, units),
+            Err(RecruitError::NoRecruitment)
+        );
+        gamemap.armys[0].building = Some(0);
+        gamemap.armys[1].building = Some(0);
+        assert_eq!(gamemap.recruit(0, 1, units), Err(RecruitError::NoUnit));
+
+        // The owner pays the base price, the hostile army twice as much
+        assert_eq!(gamemap.recruit(0, 0, units), Ok(2 * cost));
+        assert_eq!(gamemap.recruit(1, 0, units), Err(RecruitError::NoGold));
+        gamemap.armys[1].stats.gold = 4 * cost;
+        assert_eq!(gamemap.recruit(1, 0, units), Ok(4 * cost));
+        assert_eq!(gamemap.recruit(0, 0, units), Err(RecruitError::NoStock));
+        assert_eq!(gamemap.armys[0].stats.gold, cost);
+        // Only the PC army hires experienced troops
+        assert_eq!(gamemap.armys[0].troops[0].get().unit.lvl.xp, 0);
+        assert_eq!(gamemap.armys[1].troops[0].get().unit.lvl.xp, 30);
+
+        // The stock is back after a week
+        gamemap.pass_time(RECRUITMENT_DAYS * Data::DAY as u64 - 1);
+        assert_eq!(gamemap.recruit(0, 0, units), Err(RecruitError::NoStock));
+        gamemap.pass_time(1);
+        gamemap.armys[0].stats.gold = 2 * cost;
+        let cells = gamemap.armys[0].hitmap.len();
+        gamemap.armys[0].hitmap = vec![Some(0); cells];
+        assert_eq!(gamemap.recruit(0, 0, units), Err(RecruitError::ArmyFull));
+        gamemap.armys[0].recalc_army_hitmap();
+        assert_eq!(gamemap.recruit(0, 0, units), Ok(2 * cost));
+    }
+    #[test]
+    fn recruitment_section() {
+        let stock = |recruitment: &Recruitment| -> Vec<(usize, usize)> {
+            recruitment
+                .units
+                .iter()
+                .map(|unit| (unit.unit, unit.count))
+                .collect()
+        };
+        // Maps keep `unit,count` pairs, the refill counts are optional
+        let ini = "[Recruitment]\nunits = 3,2,5,1\ncost_modify = 1.5\n";
+        let (_, section) = advini::parse_for_sections(ini).remove(0);
+        let (mut recruitment, _) = Recruitment::from_section(section).unwrap();
+        assert_eq!(stock(&recruitment), vec![(3, 2), (5, 1)]);
+        assert_eq!(recruitment.cost_modify, 1.5);
+        assert!(recruitment.max_counts.is_empty());
+        recruitment.restock();
+        assert_eq!(recruitment.max_counts, vec![2, 1]);
+
+        recruitment.units[0].count = 0;
+        let (mut loaded, _) = Recruitment::from_section(recruitment.to_section()).unwrap();
+        assert_eq!(stock(&loaded), vec![(3, 0), (5, 1)]);
+        assert_eq!(loaded.max_counts, vec![2, 1]);
+        loaded.restock();
+        assert_eq!(stock(&loaded), vec![(3, 2), (5, 1)]);
     }
 }
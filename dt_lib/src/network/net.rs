@@ -1,7 +1,9 @@
 use crate::{
     battle::{
+        ai::{ai_move, auto_resolve, is_ai_controlled},
         army::{find_path, Army, TroopType},
-        battlefield::{handle_action, Action, BattleInfo},
+        battlefield::{handle_action, retreat, start_battle, Action, BattleInfo},
+        siege::{end_siege, siege_target, start_siege},
         troop::Troop,
     },
     map::{
@@ -35,6 +37,11 @@ use std::{
 pub enum ClientMessage {
     Action((usize, usize)),
     MapClick((usize, usize)),
+    /// The army of the client retreats from the battle
+    Retreat,
+    /// The battle AI plays the rest of the battle of the client for every side,
+    /// allowed only when every other army of the battle is an unclaimed PC army
+    QuickCombat,
 }
 
 #[derive(Clone, Debug)]
@@ -89,6 +96,7 @@ impl Clone for GameServer {
 }
 pub const HOST_CLIENT_ID: ClientId = ClientId::from_raw(1);
 pub const PROTOCOL_ID: u64 = 228;
+const AI_MOVES_PER_UPDATE: usize = 64;
 pub const ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
 pub static SERVER: once_cell::sync::Lazy<SocketAddr> = once_cell::sync::Lazy::new(|| {
     format!("127.0.0.1:{}", unsafe { &SETTINGS }.port)
@@ -163,6 +171,17 @@ impl GameServer {
         );
         Some(())
     }
+    /// Sends the battle and the map to every client
+    fn broadcast_state(&mut self, battle: &BattleInfo, gamemap: &GameMap) {
+        let message = ServerMessage::State((Some(battle.clone()), gamemap.clone()));
+        let size = serialized_size::<ServerMessage, _>(&message);
+        let mut output = vec![0u8; size.0];
+        serialize::<ServerMessage, ServerMessage>(message, &mut output).ok();
+        self.server.broadcast_message(
+            DefaultChannel::ReliableOrdered,
+            renet::Bytes::copy_from_slice(&output),
+        );
+    }
     /// Used by server to process client's input
     pub fn handle_client_message(
         &mut self,
@@ -207,6 +226,22 @@ impl GameServer {
                     }
                 }
             }
+            ClientMessage::Retreat => {
+                let Some(battle) = battle.as_mut() else {
+                    return;
+                };
+                let army = *client_id.and_then(|v| self.auth.get(&v)).unwrap_or(&0usize);
+                if retreat(battle, gamemap, army).is_some() {
+                    self.broadcast_state(battle, gamemap);
+                }
+            }
+            ClientMessage::QuickCombat => {
+                let Some(battle) = battle.as_mut() else {
+                    return;
+                };
+                let army = *client_id.and_then(|v| self.auth.get(&v)).unwrap_or(&0usize);
+                self.quick_combat(battle, gamemap, army);
+            }
             ClientMessage::MapClick(goal) => {
                 let army_index = client_id
                     .and_then(|v| self.auth.get(&v).cloned())
@@ -219,14 +254,19 @@ impl GameServer {
                     let diff = (pos.0 as i64 - goal.0 as i64, pos.1 as i64 - goal.1 as i64);
                     if -1 <= diff.0 && diff.0 <= 1 && -1 <= diff.1 && diff.1 <= 1 {
                         if battle.is_none() {
-                            let battle_new =
-                                BattleInfo::new(&mut gamemap.armys, target_army, army_index);
+                            let battle_new = start_battle(gamemap, target_army, army_index);
                             *battle = Some(battle_new);
                         }
                         let message = ServerMessage::ChangeMenu(Menu::Connect as usize);
                         self.try_to_send_message(gamemap, target_army, message.clone());
                         self.try_to_send_message(gamemap, army_index, message);
                     }
+                } else if let Some(building) = siege_target(gamemap, army_index, goal) {
+                    if battle.is_none() {
+                        *battle = start_siege(gamemap, army_index, building).ok();
+                    }
+                    let message = ServerMessage::ChangeMenu(Menu::Connect as usize);
+                    self.try_to_send_message(gamemap, army_index, message);
                 } else {
                     let army_pos = {
                         let Some(army) = gamemap.armys.get_mut(army_index) else {
@@ -247,6 +287,46 @@ impl GameServer {
             }
         }
     }
+    /// Whether every army of the battle, except `except`, is a PC army that no client has claimed
+    fn is_unwatched(&self, battle: &BattleInfo, gamemap: &GameMap, except: Option<usize>) -> bool {
+        battle
+            .armies
+            .iter()
+            .filter(|army| Some(**army) != except)
+            .all(|army| {
+                is_ai_controlled(&gamemap.armys[*army])
+                    && !self.auth.values().any(|claimed| claimed == army)
+            })
+    }
+    /// Quick combat asked by the army: the AI plays the battle out
+    /// if the army fights in it and no one else watches it
+    fn quick_combat(&mut self, battle: &mut BattleInfo, gamemap: &mut GameMap, army: usize) {
+        if battle.winner.is_none()
+            && battle.slot(army).is_some()
+            && self.is_unwatched(battle, gamemap, Some(army))
+        {
+            auto_resolve(battle, &mut gamemap.armys);
+            self.broadcast_state(battle, gamemap);
+        }
+    }
+    /// Lets the battle AI act for every PC army that no client has claimed, returns true if anything was played
+    fn play_ai_moves(&self, battle: &mut BattleInfo, gamemap: &mut GameMap) -> bool {
+        let mut acted = false;
+        for _ in 0..AI_MOVES_PER_UPDATE {
+            let Some((army, _)) = battle.active_unit else {
+                break;
+            };
+            if battle.winner.is_some()
+                || self.auth.values().any(|claimed| *claimed == army)
+                || !is_ai_controlled(&gamemap.armys[army])
+            {
+                break;
+            }
+            ai_move(battle, &mut gamemap.armys);
+            acted = true;
+        }
+        acted
+    }
     pub fn update(
         &mut self,
         duration: Duration,
@@ -335,6 +415,24 @@ impl GameServer {
                                 );
                             }
                         }
+                        ClientMessage::Retreat => {
+                            let (Some(battle), Some(army)) =
+                                (battle.as_mut(), self.auth.get(&client_id).copied())
+                            else {
+                                continue;
+                            };
+                            if retreat(battle, gamemap, army).is_some() {
+                                self.broadcast_state(battle, gamemap);
+                            }
+                        }
+                        ClientMessage::QuickCombat => {
+                            let (Some(battle), Some(army)) =
+                                (battle.as_mut(), self.auth.get(&client_id).copied())
+                            else {
+                                continue;
+                            };
+                            self.quick_combat(battle, gamemap, army);
+                        }
                         ClientMessage::MapClick(goal) => {
                             let Some(army_index) = self.auth.get(&client_id).and_then(|v| Some(*v))
                             else {
@@ -349,17 +447,20 @@ impl GameServer {
                                     (pos.0 as i64 - goal.0 as i64, pos.1 as i64 - goal.1 as i64);
                                 if -1 <= diff.0 && diff.0 <= 1 && -1 <= diff.1 && diff.1 <= 1 {
                                     if battle.is_none() {
-                                        let battle_new = BattleInfo::new(
-                                            &mut gamemap.armys,
-                                            target_army,
-                                            army_index,
-                                        );
+                                        let battle_new =
+                                            start_battle(gamemap, target_army, army_index);
                                         *battle = Some(battle_new);
                                     }
                                     let message = ServerMessage::ChangeMenu(Menu::Connect as usize);
                                     self.try_to_send_message(gamemap, army_index, message.clone());
                                     self.try_to_send_message(gamemap, target_army, message);
                                 }
+                            } else if let Some(building) = siege_target(gamemap, army_index, goal) {
+                                if battle.is_none() {
+                                    *battle = start_siege(gamemap, army_index, building).ok();
+                                }
+                                let message = ServerMessage::ChangeMenu(Menu::Connect as usize);
+                                self.try_to_send_message(gamemap, army_index, message);
                             } else {
                                 let army_pos = {
                                     let Some(army) = gamemap.armys.get_mut(army_index) else {
@@ -387,6 +488,25 @@ impl GameServer {
                 }
             }
         }
+        let mut captures = Vec::new();
+        if let Some(battle) = battle.as_mut() {
+            let acted = if battle.winner.is_none() && self.is_unwatched(battle, gamemap, None) {
+                auto_resolve(battle, &mut gamemap.armys);
+                true
+            } else {
+                self.play_ai_moves(battle, gamemap)
+            };
+            let resolved = match end_siege(gamemap, battle) {
+                Ok(capture) => {
+                    captures.extend(capture);
+                    true
+                }
+                Err(_) => false,
+            };
+            if acted || resolved {
+                self.broadcast_state(battle, gamemap);
+            }
+        }
         self.transport.send_packets(&mut self.server);
         let mut pause = false;
         for i in 0..=1 {
@@ -413,10 +533,21 @@ impl GameServer {
                 } else {
                     army.building = None;
                 }
+                captures.extend(gamemap.capture(i));
+                gamemap.collect_taxes(i);
                 gamemap.recalc_armies_hitboxes();
             }
-            gamemap.time.minutes += 10;
-
+            for ledger in gamemap.pass_time(10) {
+                let message = ServerMessage::Message(ledger.report());
+                self.try_to_send_message(gamemap, ledger.army, message);
+            }
+        }
+        for capture in captures {
+            for event in capture.events {
+                execute_event(event, gamemap, gameevents, units, true);
+            }
+        }
+        if !gamemap.pause {
             for i in 0..gameevents.len() {
                 if let Some(executions) = execute_event(i, gamemap, gameevents, units, false) {
                     for exec in executions {
@@ -427,7 +558,7 @@ impl GameServer {
                             }
                             Execute::StartBattle(army, player) => {
                                 if battle.is_none() {
-                                    let battle_new = BattleInfo::new(&mut gamemap.armys, army, 0);
+                                    let battle_new = start_battle(gamemap, army, 0);
                                     *battle = Some(battle_new);
                                     self.try_to_send_message(
                                         gamemap,
@@ -135,6 +135,8 @@ use crate::{
     battle::{
         army::{Army, ArmyStats},
         control::Relations,
+        rules::{BattleRules, BattleSettings, BATTLE_RULES},
+        terrain::{TerrainInfo, TERRAINS},
     },
     items,
     map::{map::TileMap, object::BuildingVariant},
@@ -254,7 +256,7 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
     let sections = parse_for_sections(path.unwrap_or("Units.ini"));
     let mut error_collector: Vec<String> = Vec::new();
     let mut req_assets = Vec::new();
-    let mut upgrades: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut upgrades: HashMap<usize, Vec<(String, u64)>> = HashMap::new();
 
     for (_, prop) in sections.iter() {
         let mut counter = None;
@@ -294,7 +296,10 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
         let mut vamp = Some(0);
         let mut regen = Some(0);
 
-        let mut next_unit: Vec<String> = Vec::new();
+        let mut lvl_stats = ModifyUnitStats::default();
+
+        let mut next_unit: [Option<String>; 3] = Default::default();
+        let mut next_unit_lvl = [1_u64; 3];
         for (k, value) in prop.iter() {
             let v = &**value;
             match &**k {
@@ -372,7 +377,14 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
                     max_xp = handle_parse::<u64>(v, &mut error_collector, "max_xp");
                 }
                 "nextunit1" | "nextunit2" | "nextunit3" => {
-                    next_unit.push(v.into());
+                    let slot = (k.as_bytes()[8] - b'1') as usize;
+                    next_unit[slot] = Some(v.into());
+                }
+                "nextunit1level" | "nextunit2level" | "nextunit3level" => {
+                    let slot = (k.as_bytes()[8] - b'1') as usize;
+                    if let Some(lvl) = handle_parse::<u64>(v, &mut error_collector, k) {
+                        next_unit_lvl[slot] = lvl;
+                    }
                 }
                 "size" => {
                     size = collect_errors(
@@ -387,6 +399,12 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
                 "globalindex" => {
                     counter = handle_parse::<usize>(v, &mut error_collector, "globalindex");
                 }
+                // Stats added on every level up, same keys as items have, see `parse_modify`
+                key if key.starts_with("d-") => {
+                    if handle_parse::<i64>(v, &mut error_collector, key).is_some() {
+                        parse_modify(&mut lvl_stats, key, v, &mut error_collector);
+                    }
+                }
                 _ => (),
             }
         }
@@ -427,7 +445,7 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
             }
         };
         let hp = hp.unwrap_or(1);
-        let xp_up = xp_up.unwrap_or(1);
+        let xp_up = xp_up.unwrap_or(140);
         let max_xp = max_xp.unwrap_or(1);
 
         let cost_hire = cost_hire.unwrap_or(1);
@@ -476,11 +494,12 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
                 icon_index: counter.unwrap() - 1,
                 unit_type,
                 next_unit: Vec::new(),
+                next_unit_lvl: Vec::new(),
                 magic_type,
                 size: size.unwrap_or((1, 1)),
                 surrender,
                 lvl: LevelUpInfo {
-                    stats: ModifyUnitStats::default(),
+                    stats: lvl_stats,
                     xp_up,
                     max_xp,
                 },
@@ -498,23 +517,43 @@ pub fn parse_units(path: Option<&str>) -> Result<(Vec<Unit>, (&'static str, Vec<
             effects: vec![],
         };
         req_assets.push(format!("unit_{}.png", counter.unwrap() - 1));
+        upgrades.insert(
+            counter.unwrap(),
+            next_unit
+                .into_iter()
+                .zip(next_unit_lvl)
+                .filter_map(|(name, lvl)| name.map(|name| (name, lvl)))
+                .collect(),
+        );
         units.push((counter.unwrap(), unit));
-        for (index, up) in upgrades.iter() {
-            let upgrade = up
-                .iter()
-                .map(|name| {
-                    units
-                        .iter()
-                        .filter(|unit| unit.1.info.name == *name)
-                        .map(|unit| unit.0)
-                        .next()
-                        .unwrap()
-                })
-                .collect();
-            units[*index].1.info.next_unit = upgrade;
-        }
     }
     units.sort_by_key(|v| v.0);
+    // Units.ini repeats some names, promotions go to the first unit with the name
+    let mut indices = HashMap::new();
+    for (index, (_, unit)) in units.iter().enumerate() {
+        let name = &unit.info.name;
+        if let Some(first) = indices.get(name) {
+            log::warn!("Unit {name} at {index} has the same name as the unit at {first}");
+            continue;
+        }
+        indices.insert(name.clone(), index);
+    }
+    for (counter, unit) in units.iter_mut() {
+        let Some(up) = upgrades.get(counter) else {
+            continue;
+        };
+        for (name, lvl) in up {
+            let Some(index) = indices.get(name) else {
+                error_collector.push(format!(
+                    "Error: unknown unit {name}; additional: NextUnit of {}",
+                    unit.info.name
+                ));
+                continue;
+            };
+            unit.info.next_unit.push(*index);
+            unit.info.next_unit_lvl.push(*lvl);
+        }
+    }
     let units = units.into_iter().map(|v| v.1).collect::<Vec<Unit>>();
     if let Ok(mut units_write) = UNITS.write() {
         units_write.append(&mut units.clone());
@@ -534,6 +573,8 @@ pub struct Settings {
     pub fullscreen: bool,
     pub init_size: (u32, u32),
     pub port: u64,
+    pub battle: BattleRules,
+    pub battle_settings: BattleSettings,
 }
 
 pub static mut SETTINGS: Settings = Settings {
@@ -543,11 +584,13 @@ pub static mut SETTINGS: Settings = Settings {
     fullscreen: true,
     init_size: (1600, 1200),
     port: 0,
+    battle: BattleRules::new(),
+    battle_settings: BattleSettings::new(),
 };
 pub static LOCALE: Lazy<Mutex<Locale>> =
     Lazy::new(|| Mutex::new(Locale::new("Rus".into(), "Eng".into())));
 
-pub fn parse_settings() -> Settings {
+pub fn parse_settings() -> Result<Settings, String> {
     let sections = parse_for_sections("Settings.ini");
     let mut max_troops: usize = 0;
     let mut locale = String::new();
@@ -555,7 +598,18 @@ pub fn parse_settings() -> Settings {
     let mut fullscreen = false;
     let mut init_size = None;
     let mut port = 0;
+    let mut battle = BattleRules::new();
+    let mut battle_settings = BattleSettings::new();
     for (sec, prop) in sections.iter() {
+        if sec == "Battle" {
+            let section_error = |err| format!("Error: [Battle] section: {err}");
+            let (rules, remaining) =
+                BattleRules::from_section(prop.clone()).map_err(section_error)?;
+            battle = rules;
+            battle_settings = BattleSettings::from_section(remaining)
+                .map_err(section_error)?
+                .0;
+        }
         for (k, value) in prop.iter() {
             match &**k {
                 "max_troops" => {
@@ -587,11 +641,13 @@ pub fn parse_settings() -> Settings {
         fullscreen,
         init_size: init_size.unwrap(),
         port,
+        battle,
+        battle_settings,
     };
     unsafe {
         SETTINGS = settings.clone();
     }
-    settings
+    Ok(settings)
 }
 
 fn parse_for_props(path: &str) -> HashMap<String, String> {
@@ -726,6 +782,7 @@ pub fn parse_items(path: Option<&str>, lang: &String) -> (&'static str, Vec<Stri
         let mut magic = None;
         let mut index = None;
         let mut bonus = None;
+        let mut duration = None;
         let itemtype_name = "";
         for (k, value) in props.iter() {
             let value = &**value;
@@ -764,151 +821,14 @@ pub fn parse_items(path: Option<&str>, lang: &String) -> (&'static str, Vec<Stri
                     }
                     .into()
                 }
-                "d-hits" => {
-                    modify.max_hp.add = add_opt(modify.max_hp.add, value.parse::<i64>().ok());
-                    modify.hp.add = add_opt(modify.hp.add, value.parse::<i64>().ok());
-                }
-                "d-attackblow" => {
-                    modify.damage.hand.add = add_opt(modify.damage.hand.add, value.parse().ok())
-                }
-                "d-attackshot" => {
-                    modify.damage.ranged.add = add_opt(modify.damage.ranged.add, value.parse().ok())
-                }
-                "d-magicpower" => {
-                    modify.damage.magic.add = add_opt(modify.damage.magic.add, value.parse().ok())
-                }
-                "d-defenceblow" => {
-                    modify.defence.hand_units.add =
-                        add_opt(modify.defence.hand_units.add, value.parse().ok())
-                }
-                "d-defenceshot" => {
-                    modify.defence.ranged_units.add =
-                        add_opt(modify.defence.ranged_units.add, value.parse().ok())
-                }
-                "d-defencemagic" => {
-                    modify.defence.magic_units.add =
-                        add_opt(modify.defence.magic_units.add, value.parse().ok())
-                }
-                "d-manevres" => {
-                    modify.max_moves.add = add_opt(modify.max_moves.add, value.parse().ok());
-                    modify.moves.add = add_opt(modify.moves.add, value.parse().ok());
-                }
-                "d-initiative" => modify.speed.add = add_opt(modify.speed.add, value.parse().ok()),
-                "d-vampirizm" => modify.vamp.add = add_opt(modify.vamp.add, value.parse().ok()),
-                "d-regen" => modify.regen.add = add_opt(modify.regen.add, value.parse().ok()),
-
-                "p-hits" => {
-                    modify.max_hp.percent_add = add_opt(
-                        modify.max_hp.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
-                    modify.hp.percent_add = add_opt(
-                        modify.hp.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
-                }
-                "p-attackblow" => {
-                    modify.damage.hand.percent_add = add_opt(
-                        modify.damage.hand.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-attackshot" => {
-                    modify.damage.ranged.percent_add = add_opt(
-                        modify.damage.ranged.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-magicpower" => {
-                    modify.damage.magic.percent_add = add_opt(
-                        modify.damage.magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-defenceblow" => {
-                    modify.defence.hand_units.percent_add = add_opt(
-                        modify.defence.hand_units.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-defenceshot" => {
-                    modify.defence.ranged_units.percent_add = add_opt(
-                        modify.defence.ranged_units.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-defencemagic" => {
-                    modify.defence.magic_units.percent_add = add_opt(
-                        modify.defence.magic_units.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-protectlife" => {
-                    modify.defence.life_magic.percent_add = add_opt(
-                        modify.defence.life_magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-protectdeath" => {
-                    modify.defence.death_magic.percent_add = add_opt(
-                        modify.defence.death_magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-protectelemental" => {
-                    modify.defence.elemental_magic.percent_add = add_opt(
-                        modify.defence.elemental_magic.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-manevres" => {
-                    modify.max_moves.percent_add = add_opt(
-                        modify.max_moves.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
-                    modify.moves.percent_add = add_opt(
-                        modify.moves.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    );
-                }
-                "p-initiative" => {
-                    modify.speed.percent_add = add_opt(
-                        modify.speed.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-vampirizm" => {
-                    modify.vamp.percent_add = add_opt(
-                        modify.vamp.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-                "p-regen" => {
-                    modify.regen.percent_add = add_opt(
-                        modify.regen.percent_add,
-                        Percent::new(value.parse().unwrap()).into(),
-                    )
-                }
-
-                "f-hits" => {
-                    modify.max_hp.set = value.parse::<i64>().ok();
-                    modify.hp.set = value.parse::<i64>().ok();
-                }
-                "f-attackblow" => modify.damage.hand.set = value.parse().ok(),
-                "f-attackshot" => modify.damage.ranged.set = value.parse().ok(),
-                "f-magicpower" => modify.damage.magic.set = value.parse().ok(),
-                "f-defenceblow" => modify.defence.hand_units.set = value.parse().ok(),
-                "f-defenceshot" => modify.defence.ranged_units.set = value.parse().ok(),
-                "f-defencemagic" => modify.defence.magic_units.set = value.parse().ok(),
-                "f-manevres" => {
-                    modify.max_moves.set = value.parse().ok();
-                    modify.moves.set = value.parse().ok();
-                }
-                "f-initiative" => modify.speed.set = value.parse().ok(),
-                "f-vampirizm" => modify.vamp.set = value.parse().ok(),
-                "f-regen" => modify.regen.add = value.parse().ok(),
                 "bonus" => bonus = Some(Bonus::from(value)),
-                _ => {}
+                "duration" => match Time::eat(value.chars()) {
+                    Ok((time, _)) => duration = Some(time),
+                    Err(_) => error_collector.push(format!("Wrong duration of item {sec}")),
+                },
+                key => {
+                    parse_modify(&mut modify, key, value, &mut error_collector);
+                }
             }
         }
         items.insert(
@@ -929,12 +849,173 @@ pub fn parse_items(path: Option<&str>, lang: &String) -> (&'static str, Vec<Stri
                 bonus,
                 itemtype: itemtype.expect(&*format!("{name}", name = name.unwrap())),
                 modify,
+                duration,
             },
         );
     }
     ("assets/Items", req_assets)
 }
 
+/// Fills `TERRAINS` from the data file, returns what could not be read
+pub fn parse_terrains(path: Option<&str>) -> Vec<String> {
+    let mut error_collector = Vec::new();
+    let mut terrains = TERRAINS.lock().unwrap();
+    terrains.clear();
+    for (sec, props) in parse_for_sections(path.unwrap_or("Terrains.ini")) {
+        let mut terrain = TerrainInfo {
+            name: sec.clone(),
+            ..Default::default()
+        };
+        for (k, value) in props.iter() {
+            let value = &**value;
+            match &**k {
+                "name" => terrain.name = value.into(),
+                "descript" => terrain.description = value.into(),
+                "tiles" => {
+                    let tiles = value.split(',').map(|tile| tile.trim().parse::<usize>());
+                    match tiles.collect() {
+                        Ok(tiles) => terrain.tiles = tiles,
+                        Err(_) => error_collector.push(format!("Wrong tiles of terrain {sec}")),
+                    }
+                }
+                "buildings" => {
+                    terrain.buildings = value.split(',').map(|name| name.trim().into()).collect()
+                }
+                key => {
+                    if !parse_modify(&mut terrain.modify, key, value, &mut error_collector) {
+                        error_collector.push(format!("Unknown key {key} of terrain {sec}"));
+                    }
+                }
+            }
+        }
+        terrains.push(terrain);
+    }
+    error_collector
+}
+
+/// Reads a d-, p- or f- key of a stat change into `modify`, false for other keys.
+/// Malformed percents are reported to `error_collector`
+fn parse_modify(
+    modify: &mut ModifyUnitStats,
+    key: &str,
+    value: &str,
+    error_collector: &mut Vec<String>,
+) -> bool {
+    let mut percent = || handle_parse::<i16>(value, &mut *error_collector, key).map(Percent::new);
+    match key {
+        "d-hits" => {
+            modify.max_hp.add = add_opt(modify.max_hp.add, value.parse::<i64>().ok());
+            modify.hp.add = add_opt(modify.hp.add, value.parse::<i64>().ok());
+        }
+        "d-attackblow" => {
+            modify.damage.hand.add = add_opt(modify.damage.hand.add, value.parse().ok())
+        }
+        "d-attackshot" => {
+            modify.damage.ranged.add = add_opt(modify.damage.ranged.add, value.parse().ok())
+        }
+        "d-magicpower" => {
+            modify.damage.magic.add = add_opt(modify.damage.magic.add, value.parse().ok())
+        }
+        "d-defenceblow" => {
+            modify.defence.hand_units.add =
+                add_opt(modify.defence.hand_units.add, value.parse().ok())
+        }
+        "d-defenceshot" => {
+            modify.defence.ranged_units.add =
+                add_opt(modify.defence.ranged_units.add, value.parse().ok())
+        }
+        "d-defencemagic" => {
+            modify.defence.magic_units.add =
+                add_opt(modify.defence.magic_units.add, value.parse().ok())
+        }
+        "d-manevres" => {
+            modify.max_moves.add = add_opt(modify.max_moves.add, value.parse().ok());
+            modify.moves.add = add_opt(modify.moves.add, value.parse().ok());
+        }
+        "d-initiative" => modify.speed.add = add_opt(modify.speed.add, value.parse().ok()),
+        "d-protectlife" => {
+            modify.defence.life_magic.add =
+                add_opt(modify.defence.life_magic.add, value.parse().ok())
+        }
+        "d-protectdeath" => {
+            modify.defence.death_magic.add =
+                add_opt(modify.defence.death_magic.add, value.parse().ok())
+        }
+        "d-protectelemental" => {
+            modify.defence.elemental_magic.add =
+                add_opt(modify.defence.elemental_magic.add, value.parse().ok())
+        }
+        "d-vampirizm" => modify.vamp.add = add_opt(modify.vamp.add, value.parse().ok()),
+        "d-regen" => modify.regen.add = add_opt(modify.regen.add, value.parse().ok()),
+
+        "p-hits" => {
+            let percent = percent();
+            modify.max_hp.percent_add = add_opt(modify.max_hp.percent_add, percent);
+            modify.hp.percent_add = add_opt(modify.hp.percent_add, percent);
+        }
+        "p-attackblow" => {
+            modify.damage.hand.percent_add = add_opt(modify.damage.hand.percent_add, percent())
+        }
+        "p-attackshot" => {
+            modify.damage.ranged.percent_add = add_opt(modify.damage.ranged.percent_add, percent())
+        }
+        "p-magicpower" => {
+            modify.damage.magic.percent_add = add_opt(modify.damage.magic.percent_add, percent())
+        }
+        "p-defenceblow" => {
+            modify.defence.hand_units.percent_add =
+                add_opt(modify.defence.hand_units.percent_add, percent())
+        }
+        "p-defenceshot" => {
+            modify.defence.ranged_units.percent_add =
+                add_opt(modify.defence.ranged_units.percent_add, percent())
+        }
+        "p-defencemagic" => {
+            modify.defence.magic_units.percent_add =
+                add_opt(modify.defence.magic_units.percent_add, percent())
+        }
+        "p-protectlife" => {
+            modify.defence.life_magic.percent_add =
+                add_opt(modify.defence.life_magic.percent_add, percent())
+        }
+        "p-protectdeath" => {
+            modify.defence.death_magic.percent_add =
+                add_opt(modify.defence.death_magic.percent_add, percent())
+        }
+        "p-protectelemental" => {
+            modify.defence.elemental_magic.percent_add =
+                add_opt(modify.defence.elemental_magic.percent_add, percent())
+        }
+        "p-manevres" => {
+            let percent = percent();
+            modify.max_moves.percent_add = add_opt(modify.max_moves.percent_add, percent);
+            modify.moves.percent_add = add_opt(modify.moves.percent_add, percent);
+        }
+        "p-initiative" => modify.speed.percent_add = add_opt(modify.speed.percent_add, percent()),
+        "p-vampirizm" => modify.vamp.percent_add = add_opt(modify.vamp.percent_add, percent()),
+        "p-regen" => modify.regen.percent_add = add_opt(modify.regen.percent_add, percent()),
+
+        "f-hits" => {
+            modify.max_hp.set = value.parse::<i64>().ok();
+            modify.hp.set = value.parse::<i64>().ok();
+        }
+        "f-attackblow" => modify.damage.hand.set = value.parse().ok(),
+        "f-attackshot" => modify.damage.ranged.set = value.parse().ok(),
+        "f-magicpower" => modify.damage.magic.set = value.parse().ok(),
+        "f-defenceblow" => modify.defence.hand_units.set = value.parse().ok(),
+        "f-defenceshot" => modify.defence.ranged_units.set = value.parse().ok(),
+        "f-defencemagic" => modify.defence.magic_units.set = value.parse().ok(),
+        "f-manevres" => {
+            modify.max_moves.set = value.parse().ok();
+            modify.moves.set = value.parse().ok();
+        }
+        "f-initiative" => modify.speed.set = value.parse().ok(),
+        "f-vampirizm" => modify.vamp.set = value.parse().ok(),
+        "f-regen" => modify.regen.add = value.parse().ok(),
+        _ => return false,
+    }
+    true
+}
 trait IsRus {
     fn is_rus_alphabet(&self) -> bool;
 }
@@ -1082,6 +1163,7 @@ fn parse_mapdata(
                                 was_payed: true,
                                 pos: UnitPos::from_index(0),
                                 custom_name: Some(things.1.into()),
+                                battle_stats: Default::default(),
                             };
                             main = Some(SendMut::new(troop));
                         }
@@ -1144,10 +1226,7 @@ fn parse_mapdata(
                                 .map(|(id, num)| {
                                     (id.parse().unwrap(), num.parse::<usize>().unwrap())
                                 })
-                                .map(|(id, num)| RecruitUnit {
-                                    unit: id,
-                                    count: num,
-                                })
+                                .map(|(id, num)| RecruitUnit::new(id, num))
                                 .collect();
                         }
                         "pos" => {
@@ -1172,7 +1251,7 @@ fn parse_mapdata(
                     .into();
                 }
                 if !units.is_empty() {
-                    recruitment = Recruitment { cost_modify, units }.into();
+                    recruitment = Recruitment::new(units, cost_modify).into();
                 }
                 buildings.push((
                     id.unwrap(),
@@ -1248,7 +1327,16 @@ pub fn parse_story(
     // Eventsandlights
     let mut events_path = None;
 
+    // Battle
+    let mut battle_rules = BATTLE_RULES.clone();
+
     for (sec, props) in parse_for_sections(&format!("{map_path}")) {
+        if sec == "Battle" {
+            match BattleRules::from_section(props.clone()) {
+                Ok((rules, _)) => battle_rules = rules,
+                Err(err) => err_coll.push(format!("Error: [Battle] section: {err}")),
+            }
+        }
         for prop in props {
             let prop = (prop.0, process_locale(prop.1, &mut locale));
             match &*prop.0 {
@@ -1297,6 +1385,7 @@ pub fn parse_story(
         tilemap: mapdata.0,
         buildings: mapdata.2,
         time: start_time,
+        battle_rules,
         ..Default::default()
     };
     (gamemap, events)
@@ -0,0 +1,84 @@
+use crate::{
+    battle::{
+        army::Army,
+        control::{Control, Relations},
+        troop::Troop,
+        ArmyStats,
+    },
+    map::object::{BuildingVariant, MapBuildingdata},
+    parse::parse_units,
+    units::unit::Unit,
+};
+use once_cell::sync::Lazy;
+
+/// Units.ini, parsed once for all tests
+pub static TEST_UNITS: Lazy<Vec<Unit>> = Lazy::new(|| {
+    let Ok((units, _)) = parse_units(Some("dt/Units.ini")) else {
+        panic!("Unit parsing error")
+    };
+    units
+});
+
+/// Index of the first unit with the name in Units.ini
+pub fn unit_index(name: &str) -> usize {
+    TEST_UNITS
+        .iter()
+        .position(|unit| unit.info.name == name)
+        .expect("No such unit in Units.ini")
+}
+pub fn unit(name: &str) -> Unit {
+    TEST_UNITS[unit_index(name)].clone()
+}
+pub fn knight() -> Unit {
+    unit("Рыцарь")
+}
+pub fn wounded(mut unit: Unit, hp: i64) -> Unit {
+    unit.stats.hp = hp;
+    unit.recalc();
+    unit
+}
+/// Army controlled by the PC with the troops and gold
+pub fn army_of(troops: Vec<Troop>, gold: u64) -> Army {
+    let mut army = Army::new(
+        vec![],
+        ArmyStats {
+            gold,
+            ..Default::default()
+        },
+        vec![],
+        (0, 0),
+        true,
+        Control::PC,
+    );
+    for troop in troops {
+        army.add_troop(troop.into()).unwrap();
+    }
+    army
+}
+/// Castle owned by the army, without a garrison, market or recruitment
+pub fn building(owner: usize, gold_income: u64) -> MapBuildingdata {
+    MapBuildingdata {
+        name: String::new(),
+        desc: String::new(),
+        owner_name: String::new(),
+        id: 0,
+        events: vec![],
+        variant: BuildingVariant::Castle,
+        market: None,
+        recruitment: None,
+        pos: (0, 0),
+        owner: Some(owner),
+        garrison: vec![],
+        additional_defense: 0,
+        gold_income,
+        mana_income: 5,
+        spells_to_learn: vec![],
+        relations: Relations {
+            player: 0,
+            ally: 0,
+            neighbour: 0,
+            enemy: 0,
+        },
+        group: 0,
+    }
+}
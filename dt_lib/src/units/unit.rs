@@ -2,6 +2,7 @@ use crate::{
     battle::{
         army::{MAX_LINES, MAX_TROOPS},
         battlefield::{field_type, BattleInfo, Field},
+        event::{unit_ref, BattleEvent, UnitSnapshot},
     },
     parse::LOCALE,
 };
@@ -14,6 +15,7 @@ use crate::{
     bonuses::*,
     effects::effect::*,
     items::item::Item,
+    time::time::Time,
     units::unit::{MagicDirection::*, MagicType::*},
 };
 use advini::*;
@@ -162,6 +164,17 @@ impl LevelUpInfo {
             max_xp: 0,
         }
     }
+    /// XP needed to go from `lvl` to the next level (Levpler in Units.ini): levels are
+    /// counted from 1 there, y → y+1 costs `max_xp` up to y = 2 and `max_xp * (xp_up / 100) ^ y` after
+    pub fn level_cost(&self, lvl: u64) -> u64 {
+        let y = lvl + 1;
+        if y <= 2 {
+            return self.max_xp;
+        }
+        let multiplier = self.xp_up.max(0) as f64 / 100.;
+        let cost = self.max_xp as f64 * multiplier.powi(y as i32);
+        cost.round().max(1.) as u64
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -175,6 +188,8 @@ pub struct UnitInfo {
     pub size: (usize, usize),
     pub unit_type: UnitType,
     pub next_unit: Vec<usize>,
+    /// Level needed to promote to the unit with the same index in `next_unit`
+    pub next_unit_lvl: Vec<u64>,
     pub magic_type: Option<MagicType>,
     pub surrender: Option<u64>,
     pub lvl: LevelUpInfo,
@@ -190,6 +205,7 @@ impl UnitInfo {
             size: (1, 1),
             unit_type: UnitType::People,
             next_unit: Vec::new(),
+            next_unit_lvl: Vec::new(),
             magic_type: None,
             surrender: None,
             lvl: LevelUpInfo::empty(),
@@ -225,6 +241,23 @@ impl UnitLvl {
     }
 }
 
+/// What changed after the unit gained levels
+#[derive(Clone, Debug)]
+#[alkahest(Deserialize, Serialize, SerializeRef, Formula)]
+pub struct LevelUpReport {
+    pub from_lvl: u64,
+    pub to_lvl: u64,
+    pub xp_spent: u64,
+    pub max_xp: u64,
+    pub stats_before: UnitStats,
+    pub stats_after: UnitStats,
+}
+impl LevelUpReport {
+    pub fn gained(&self) -> u64 {
+        self.to_lvl - self.from_lvl
+    }
+}
+
 #[derive(
     Debug,
     Copy,
@@ -244,7 +277,7 @@ impl UnitLvl {
 pub struct UnitPos(pub usize, pub usize);
 impl UnitPos {
     pub fn from_index(index: usize) -> Self {
-        let max_troops = *MAX_TROOPS / MAX_LINES;
+        let max_troops = *MAX_TROOPS / *MAX_LINES;
         Self(index % max_troops, index / max_troops)
     }
 }
@@ -255,7 +288,7 @@ impl Into<(usize, usize)> for UnitPos {
 }
 impl Into<usize> for UnitPos {
     fn into(self) -> usize {
-        self.0 + self.1 * *MAX_TROOPS / 2
+        self.0 + self.1 * (*MAX_TROOPS / *MAX_LINES)
     }
 }
 
@@ -455,12 +488,32 @@ fn get_magic_direction(magic_type: MagicType) -> MagicDirection {
         Elemental(direction) => direction,
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ActionResult {
     Buff,
     Debuff,
     Melee,
     Ranged,
     Move,
+    Retreat,
+}
+/// Expected result of `Unit::attack`, see `Unit::preview_attack`
+#[derive(Clone, Debug)]
+pub struct AttackPreview {
+    pub result: ActionResult,
+    /// Damage to the target by type, after defence and bonuses
+    pub damage: Power,
+    /// HP the target would lose
+    pub hp_lost: u64,
+    pub healed: u64,
+    pub target_dies: bool,
+    /// Effects the target would get
+    pub effects: Vec<EffectKind>,
+    /// Damage the attacker would take back from Counterblow
+    pub counter_damage: Power,
+    /// HP the attacker would lose, from Counterblow or from bonuses like DeathCurse
+    pub attacker_hp_lost: u64,
+    pub attacker_dies: bool,
 }
 impl Unit {
     pub fn recalc(&mut self) {
@@ -516,7 +569,7 @@ impl Unit {
             && is_enemy
         {
             true
-        } else if damage.hand > 0 && !is_in_back && target_pos.1 == 1 && is_enemy {
+        } else if damage.hand > 0 && !is_in_back && target_pos.1 + 1 == *MAX_LINES && is_enemy {
             true
         } else {
             match self.info.magic_type {
@@ -609,7 +662,8 @@ impl Unit {
             && (!me_in_reserve || self.bonus.can_attack_from_reserve())
             && !enemy_in_reserve)
             || (!is_enemy && (both_not_in_reserve || both_in_reserve));
-        return if !a {
+        let target_hp = target.modified.hp;
+        let result = if !a {
             None
         } else if damage.ranged > 0
             && (target_pos.1 == my_pos.1 && abs(target_pos.0 as i64 - my_pos.0 as i64) < 2
@@ -620,7 +674,7 @@ impl Unit {
             damage.magic = 0;
             target.being_attacked(&damage, self, target_pos, my_pos, battle);
             Some(ActionResult::Ranged)
-        } else if damage.hand > 0 && !is_in_back && target_pos.1 == 1 && is_enemy {
+        } else if damage.hand > 0 && !is_in_back && target_pos.1 + 1 == *MAX_LINES && is_enemy {
             damage.ranged = 0;
             damage.magic = 0;
             target.being_attacked(&damage, self, target_pos, my_pos, battle);
@@ -637,7 +691,7 @@ impl Unit {
                     (Death(_) | Life(_), true) => {
                         if is_in_back {
                             magic_attack(
-                                self, target, damage, magic_type, my_pos, target_pos,
+                                self, target, damage, magic_type, target_pos, my_pos,
                                 battle,
                             )
                         } else {
@@ -662,7 +716,7 @@ impl Unit {
                     if is_in_back {
                         match magic_type {
                             Death(_) | Life(_) => magic_attack(
-                                self, target, damage, magic_type, my_pos, target_pos,
+                                self, target, damage, magic_type, target_pos, my_pos,
                                 battle,
                             ),
                             Elemental(_) => elemental_attack(
@@ -680,7 +734,7 @@ impl Unit {
                 (StrikeOnly, _, true) => {
                     damage.hand = 0;
                     damage.ranged = 0;
-                    target.being_attacked(&damage, self, my_pos, target_pos, battle);
+                    target.being_attacked(&damage, self, target_pos, my_pos, battle);
                     Some(ActionResult::Debuff)
                 }
                 (BlessOnly, _, false) => match magic_type {
@@ -692,8 +746,63 @@ impl Unit {
                 }
                 _ => None,
             }
-        }
+        };
+        self.drink_blood(target, target_hp);
+        result
 	}
+    /// Plays `Unit::attack` on copies of both units and a detached battle,
+    /// so neither the units nor the battle events are touched
+    pub fn preview_attack(
+        &self,
+        target: &Unit,
+        target_pos: UnitPos,
+        my_pos: UnitPos,
+        battle: &BattleInfo,
+    ) -> Option<AttackPreview> {
+        let battle = battle.detached();
+        let (mut me_after, mut target_after) = (self.clone(), target.clone());
+        let result = me_after.attack(&mut target_after, target_pos, my_pos, &battle)?;
+        let (me, them) = (unit_ref(self, my_pos), unit_ref(target, target_pos));
+        let mut damage = Power::empty();
+        let mut counter_damage = Power::empty();
+        for event in battle.take_events() {
+            if let BattleEvent::Damage {
+                from: Some(from),
+                to,
+                damage: dealt,
+                ..
+            } = event
+            {
+                if from == me && to == them {
+                    damage = damage + dealt;
+                } else if from == them && to == me {
+                    counter_damage = counter_damage + dealt;
+                }
+            }
+        }
+        let effects = UnitSnapshot::new(target)
+            .changes(&target_after, them, Some(me))
+            .into_iter()
+            .filter_map(|event| match event {
+                BattleEvent::EffectAdded { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .collect();
+        let lost = |before: &Unit, after: &Unit| {
+            (before.modified.hp.max(0) - after.modified.hp.max(0)).max(0) as u64
+        };
+        Some(AttackPreview {
+            result,
+            damage,
+            hp_lost: lost(target, &target_after),
+            healed: lost(&target_after, target),
+            target_dies: target_after.is_dead(),
+            effects,
+            counter_damage,
+            attacker_hp_lost: lost(self, &me_after),
+            attacker_dies: me_after.is_dead(),
+        })
+    }
 
     pub fn heal(&mut self, amount: u64) -> bool {
         let effected = self.modified;
@@ -708,12 +817,74 @@ impl Unit {
         return false;
     }
 
+    /// Spends XP on as many levels as it can buy, each level applies `LevelUpInfo::stats`
+    /// to the base stats, the next level costs `LevelUpInfo::level_cost`
+    pub fn level_up(&mut self) -> Option<LevelUpReport> {
+        let (from_lvl, stats_before) = (self.lvl.lvl, self.stats);
+        let mut xp_spent = 0;
+        while self.lvl.max_xp > 0 && self.lvl.xp >= self.lvl.max_xp {
+            self.lvl.xp -= self.lvl.max_xp;
+            xp_spent += self.lvl.max_xp;
+            self.lvl.lvl += 1;
+            self.lvl.max_xp = self.info.lvl.level_cost(self.lvl.lvl);
+            self.stats = self.info.lvl.stats.apply(&self.stats);
+        }
+        if self.lvl.lvl == from_lvl {
+            return None;
+        }
+        self.recalc();
+        Some(LevelUpReport {
+            from_lvl,
+            to_lvl: self.lvl.lvl,
+            xp_spent,
+            max_xp: self.lvl.max_xp,
+            stats_before,
+            stats_after: self.stats,
+        })
+    }
+    pub fn add_xp(&mut self, xp: u64) -> Option<LevelUpReport> {
+        self.lvl.xp += xp;
+        self.level_up()
+    }
     pub fn get_effected_stats(&self) -> UnitStats {
         self.modified
     }
     pub fn is_dead(&self) -> bool {
         self.modified.hp < 1
     }
+    /// Whether the unit is of flesh and blood: neither undead nor mecha,
+    /// nor a ghost or a dead one by its bonus.
+    /// Only such units regenerate and lose blood to vampirism
+    pub fn has_blood(&self) -> bool {
+        !matches!(self.info.unit_type, UnitType::Undead | UnitType::Mecha)
+            && !matches!(
+                self.get_bonus(),
+                Bonus::Ghost | Bonus::DeadDodging | Bonus::FastDead
+            )
+    }
+    /// Heals the attacker by `vamp` percents of the HP the target lost to its attack
+    fn drink_blood(&mut self, target: &Unit, target_hp: i64) {
+        let lost = target_hp.max(0) - target.modified.hp.max(0);
+        if lost > 0 && !self.is_dead() && target.has_blood() {
+            let blood = self.modified.vamp.calc(lost as u64);
+            if blood > 0 {
+                self.heal(blood);
+            }
+        }
+    }
+    /// Restores `regen` percents of max HP, called each battle round
+    /// and each hour of world time, see `Army::regenerate`
+    pub fn regenerate(&mut self) -> bool {
+        if self.is_dead() || !self.has_blood() || self.modified.hp >= self.modified.max_hp {
+            return false;
+        }
+        let amount = self.modified.regen.calc(self.modified.max_hp.max(0) as u64);
+        if amount == 0 {
+            return false;
+        }
+        self.heal(amount);
+        true
+    }
     pub fn has_effect_kind(&self, kind: EffectKind) -> bool {
         for effect in &self.effects {
             if effect.get_kind() == kind {
@@ -722,6 +893,21 @@ impl Unit {
         }
         return false;
     }
+    /// Ends every effect of the kind and takes its stats back
+    pub fn remove_effect_kind(&mut self, kind: EffectKind) {
+        let mut effects = std::mem::take(&mut self.effects);
+        effects.retain_mut(|effect| {
+            if effect.get_kind() == kind {
+                effect.kill(self);
+                false
+            } else {
+                true
+            }
+        });
+        effects.append(&mut self.effects);
+        self.effects = effects;
+        self.recalc();
+    }
     pub fn kill(&mut self) {
         self.stats.hp = -self.modified.hp;
         self.recalc();
@@ -788,7 +974,7 @@ impl Unit {
         let corrected_damage = self.correct_damage(damage, sender.info.magic_type);
         let unit_bonus = sender.get_bonus();
         let corrected_damage =
-            unit_bonus.on_attacking(corrected_damage, self, sender, my_pos, attacker_pos);
+            unit_bonus.on_attacking(corrected_damage, self, sender, my_pos, attacker_pos, battle);
         let corrected_damage = self.get_bonus().on_attacked(
             corrected_damage,
             self,
@@ -802,12 +988,19 @@ impl Unit {
         if corrected_damage_units == 0 {
             corrected_damage_units = 1;
         }
+        let hp_before = self.modified.hp;
         if corrected_damage_units as i64 > self.modified.hp {
             self.stats.hp = -self.modified.hp;
         } else {
             self.stats.hp -= corrected_damage_units as i64;
         }
         self.recalc();
+        battle.emit(BattleEvent::Damage {
+            from: Some(unit_ref(sender, attacker_pos)),
+            to: unit_ref(self, my_pos),
+            damage: corrected_damage,
+            hp: (hp_before.max(0) - self.modified.hp.max(0)).max(0) as u64,
+        });
         corrected_damage_units
     }
     pub fn correct_damage(&self, damage: &Power, magic_type: Option<MagicType>) -> Power {
@@ -833,22 +1026,34 @@ impl Unit {
                 .calc(damage.hand.saturating_sub(defence.hand_units)),
         }
     }
-    pub fn tick(&mut self) -> bool {
-        let mut i = 0;
-        loop {
-            if i >= self.effects.len() {
-                break;
+    /// Ends the effects that expired by the world time, see `TimedEffect`
+    pub fn on_time(&mut self, time: Time) {
+        let mut effects = std::mem::take(&mut self.effects);
+        effects.retain_mut(|effect| {
+            if effect.on_time(time) && effect.is_dead() {
+                effect.kill(self);
+                false
+            } else {
+                true
             }
-            let mut effect = self.effects.remove(i);
+        });
+        effects.append(&mut self.effects);
+        self.effects = effects;
+        self.recalc();
+    }
+    pub fn tick(&mut self) -> bool {
+        let mut effects = std::mem::take(&mut self.effects);
+        effects.retain_mut(|effect| {
             effect.tick(self);
             if effect.is_dead() {
                 effect.kill(self);
-                i -= 1;
+                false
             } else {
-                self.effects.push(effect);
-            };
-            i += 1;
-        }
+                true
+            }
+        });
+        effects.append(&mut self.effects);
+        self.effects = effects;
         self.get_bonus().on_tick(self);
         self.recalc();
         true
@@ -1037,3 +1242,237 @@ pub fn calclate_unit_power(unit: &Unit) -> f32 {
         + vamp_points * 3.
         + health_points * bonus_health_modifier
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        battle::{army::Army, control::Control, troop::Troop, ArmyStats},
+        map::map::GameMap,
+        parse::parse_items,
+        test_helpers::{knight, unit_index, wounded, TEST_UNITS},
+        time::time::Data,
+        units::unitstats::Modify,
+    };
+    use alkahest::{deserialize, serialize, serialized_size};
+
+    #[test]
+    fn level_up_once() {
+        let mut unit = knight();
+        assert_eq!(unit.lvl.max_xp, 100);
+        assert!(unit.add_xp(99).is_none());
+        let report = unit.add_xp(1).expect("Knight should level up");
+        assert_eq!(report.gained(), 1);
+        assert_eq!(unit.lvl.xp, 0);
+        assert_eq!(unit.lvl.max_xp, 100);
+        assert_eq!(unit.stats.max_hp, 88);
+        assert_eq!(unit.stats.hp, 88);
+        assert_eq!(unit.stats.damage.hand, 53);
+        assert_eq!(unit.stats.defence.hand_units, 17);
+        assert_eq!(unit.stats.defence.ranged_units, 12);
+        assert_eq!(unit.stats.defence.life_magic.get(), 18);
+        assert_eq!(unit.stats.speed, 10);
+        assert_eq!(unit.modified.max_hp, 88);
+    }
+    #[test]
+    fn level_up_many() {
+        let mut unit = knight();
+        // Levpler 150 only kicks in after the second level: 100, 100, 100 * 1.5^3, 100 * 1.5^4
+        assert_eq!(unit.info.lvl.level_cost(1), 100);
+        assert_eq!(unit.info.lvl.level_cost(2), 338);
+        let report = unit
+            .add_xp(100 + 100 + 338 + 10)
+            .expect("Knight should level up");
+        assert_eq!(report.from_lvl, 0);
+        assert_eq!(report.to_lvl, 3);
+        assert_eq!(report.xp_spent, 538);
+        assert_eq!(unit.lvl.xp, 10);
+        assert_eq!(unit.lvl.max_xp, 506);
+        assert_eq!(report.stats_before.max_hp, 80);
+        assert_eq!(report.stats_after.max_hp, 104);
+        assert_eq!(unit.stats.damage.hand, 69);
+    }
+    #[test]
+    fn promotion() {
+        let units = &*TEST_UNITS;
+        let militia = unit_index("Ополченец");
+        let guard = unit_index("Стражник");
+        let infantry = unit_index("Пехотинец");
+        let mut army = Army::new(
+            vec![],
+            ArmyStats::default(),
+            vec![],
+            (0, 0),
+            true,
+            Control::PC,
+        );
+        army.add_troop(Troop::new(units[militia].clone()).into())
+            .unwrap();
+        assert!(army.troops[0].get().promotions(units).is_empty());
+        assert!(army.promote_troop(0, guard, units).is_err());
+        {
+            let troop = &mut army.troops[0].get();
+            troop.custom_name = Some("Иван".into());
+            troop.unit.add_xp(70);
+            assert_eq!(troop.promotions(units), vec![guard, infantry]);
+        }
+        army.promote_troop(0, guard, units).unwrap();
+        let troop = army.troops[0].get();
+        assert_eq!(troop.unit.info.name, "Стражник");
+        assert_eq!(troop.custom_name.as_deref(), Some("Иван"));
+        assert_eq!(troop.unit.lvl.xp, 10);
+        assert_eq!(troop.unit.stats.hp, units[guard].stats.max_hp);
+        let index: usize = troop.pos.into();
+        assert_eq!(army.hitmap[index], Some(0));
+        // Units.ini repeats the name, the promotion goes to the first unit
+        let cuirassier = unit_index("Кирасир");
+        let knight = unit_index("Рыцарь");
+        assert_eq!(units[cuirassier].info.next_unit, vec![knight]);
+    }
+    #[test]
+    fn attack_preview() {
+        let battle = BattleInfo::default();
+        let pos = UnitPos::from_index(7);
+        for target in TEST_UNITS.iter().cloned() {
+            let mut me = knight();
+            let mut target = target;
+            target.army = 1;
+            let preview = me.preview_attack(&target, pos, pos, &battle);
+            assert!(battle.take_events().is_empty());
+            let hp = (me.modified.hp.max(0), target.modified.hp.max(0));
+            let result = me.attack(&mut target, pos, pos, &battle);
+            assert_eq!(preview.as_ref().map(|preview| preview.result), result);
+            let Some(preview) = preview else {
+                continue;
+            };
+            assert_eq!(preview.hp_lost as i64, hp.1 - target.modified.hp.max(0));
+            assert_eq!(preview.target_dies, target.is_dead());
+            assert_eq!(
+                preview.attacker_hp_lost as i64,
+                hp.0 - me.modified.hp.max(0)
+            );
+            assert_eq!(preview.attacker_dies, me.is_dead());
+        }
+    }
+    #[test]
+    fn vampirism() {
+        let battle = BattleInfo::default();
+        let pos = UnitPos::from_index(7);
+        let mut vampire = wounded(knight(), 40);
+        vampire.stats.vamp = Percent::new(50);
+        vampire.recalc();
+        let mut target = knight();
+        target.army = 1;
+        vampire.attack(&mut target, pos, pos, &battle);
+        assert_eq!(target.modified.hp, 50);
+        assert_eq!(vampire.modified.hp, 55);
+        for unit_type in [UnitType::Undead, UnitType::Mecha] {
+            let mut target = knight();
+            target.army = 1;
+            target.info.unit_type = unit_type;
+            vampire.attack(&mut target, pos, pos, &battle);
+            assert_eq!(target.modified.hp, 50);
+            assert_eq!(vampire.modified.hp, 55);
+        }
+    }
+    #[test]
+    fn regeneration() {
+        let mut unit = wounded(knight(), 40);
+        assert!(!unit.regenerate());
+        unit.stats.regen = Percent::new(10);
+        unit.recalc();
+        assert!(unit.regenerate());
+        assert_eq!(unit.modified.hp, 48);
+        let mut undead = unit.clone();
+        undead.info.unit_type = UnitType::Undead;
+        assert!(!undead.regenerate());
+        let mut ghost = unit.clone();
+        ghost.bonus = Bonus::Ghost;
+        assert!(!ghost.regenerate());
+        let mut dead = wounded(unit.clone(), 0);
+        assert!(!dead.regenerate());
+        assert_eq!(dead.modified.hp, 0);
+
+        let mut army = Army::new(
+            vec![],
+            ArmyStats::default(),
+            vec![],
+            (0, 0),
+            true,
+            Control::PC,
+        );
+        army.add_troop(Troop::new(unit).into()).unwrap();
+        let mut gamemap = GameMap::default();
+        gamemap.armys.push(army);
+        gamemap.pass_time(50);
+        assert_eq!(gamemap.armys[0].troops[0].get().unit.modified.hp, 48);
+        gamemap.pass_time(130);
+        assert_eq!(gamemap.time.minutes, 180);
+        assert_eq!(gamemap.armys[0].troops[0].get().unit.modified.hp, 72);
+    }
+    #[test]
+    fn timed_effects() {
+        let day = Time::new(Data::DAY as u64);
+        let mut unit = knight();
+        let mut modify = ModifyUnitStats::default();
+        modify.defence.hand_units = *Modify::default().add(5);
+        let effect = TimedEffect::new(modify, EffectKind::Potion, Time::new(0), day + day);
+        let message: Effect = effect.into();
+        let size = serialized_size::<Effect, _>(&message);
+        let mut output = vec![0u8; size.0];
+        serialize::<Effect, Effect>(message, &mut output).unwrap();
+        let Ok(Effect::TimedEffect(saved)) = deserialize::<Effect, Effect>(&output) else {
+            panic!("Timed effect is not saved")
+        };
+        assert_eq!(saved.until, day + day);
+
+        unit.add_effect(effect);
+        assert_eq!(unit.modified.defence.hand_units, 20);
+        unit.tick();
+        let mut troop = Troop::new(unit);
+        troop.on_battle_end();
+        assert_eq!(troop.unit.modified.defence.hand_units, 20);
+        let mut army = Army::new(
+            vec![],
+            ArmyStats::default(),
+            vec![],
+            (0, 0),
+            true,
+            Control::PC,
+        );
+        army.add_troop(troop.into()).unwrap();
+        let mut gamemap = GameMap::default();
+        gamemap.armys.push(army);
+        gamemap.pass_time(day.minutes);
+        assert_eq!(gamemap.armys[0].troops[0].get().unit.modified.defence.hand_units, 20);
+        gamemap.pass_time(day.minutes);
+        let unit = &gamemap.armys[0].troops[0].get().unit;
+        assert_eq!(unit.modified.defence.hand_units, 15);
+        assert!(unit.effects.is_empty());
+    }
+    #[test]
+    fn drinking_potions() {
+        let _ = parse_items(Some("dt/Rus_Artefacts.ini"), &"Rus".into());
+        let mut army = Army::new(
+            vec![],
+            ArmyStats::default(),
+            vec![],
+            (0, 0),
+            true,
+            Control::PC,
+        );
+        army.add_troop(Troop::new(knight()).into()).unwrap();
+        army.add_item(Item { index: 1 });
+        army.add_item(Item { index: 95 });
+        assert!(army.drink_item(0, 0, Time::new(0)).is_err());
+        army.drink_item(1, 0, Time::new(0)).unwrap();
+        assert_eq!(army.inventory.len(), 1);
+        let troop = army.troops[0].get();
+        assert_eq!(troop.unit.modified.max_moves, knight().modified.max_moves + 1);
+        let Some(Effect::TimedEffect(potion)) = troop.unit.effects.last() else {
+            panic!("Potion gives no timed effect")
+        };
+        assert_eq!(potion.until, Time::new(Data::DAY as u64));
+        assert_eq!(potion.kind, EffectKind::Potion);
+    }
+}
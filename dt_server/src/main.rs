@@ -54,7 +54,9 @@ use dt_lib::{
         tile::*,
     },
     network::net::*,
-    parse::{parse_items, parse_objects, parse_settings, parse_story, parse_units},
+    parse::{
+        parse_items, parse_objects, parse_settings, parse_story, parse_terrains, parse_units,
+    },
     time::time::Data as TimeData,
     units::{
         unit::{ActionResult, Unit, UnitPos},
@@ -103,8 +105,9 @@ fn gen_army(army_num: usize, units: &Vec<Unit>) -> Army {
 }
 static UNITS: LazyLock<Vec<Unit>> = LazyLock::new(|| parse_units(None).unwrap().0);
 fn setup() -> State {
-    let settings = parse_settings();
+    let settings = parse_settings().unwrap();
     let _ = parse_items(None, &settings.locale);
+    let _ = parse_terrains(None);
     State {
         hotel: Arc::new(Mutex::new(Hotel::new())),
     }
@@ -10,7 +10,10 @@ use dt_lib::{
         tile::*,
     },
     network::net::*,
-    parse::{collect_errors, parse_items, parse_objects, parse_settings, parse_story, parse_units},
+    parse::{
+        collect_errors, parse_items, parse_objects, parse_settings, parse_story, parse_terrains,
+        parse_units,
+    },
     time::time::Data as TimeData,
     units::{
         unit::{ActionResult, Unit, UnitPos},
@@ -108,7 +111,10 @@ async fn load_assets(req_assets_list: &[(&str, Vec<String>)]) -> Assets {
     Assets::new(asset_names.into_iter().zip(assets).collect())
 }
 async fn game_init() -> State {
-    let settings = parse_settings();
+    let settings = parse_settings().unwrap_or_else(|err| {
+        error!("{}", err);
+        panic!("{}", err);
+    });
     {
         let locale = &mut LOCALE.write().unwrap();
         locale.set_lang((&settings.locale, &settings.additional_locale));
@@ -116,6 +122,9 @@ async fn game_init() -> State {
     }
     let assets = {
         let req_assets_items = parse_items(None, &settings.locale);
+        for err in parse_terrains(None) {
+            error!("{}", err);
+        }
         let res = parse_units(None);
         if let Err(err) = res {
             error!("{}", err);
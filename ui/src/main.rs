@@ -4,14 +4,16 @@
 
 use alkahest::{serialize, serialized_size};
 use dt_lib::{
-    battle::{army::*, battlefield::*, troop::Troop},
+    battle::{army::*, battlefield::*, siege::*, troop::Troop},
     items::item::*,
     locale::{parse_locale, Locale},
     map::{
         convert::{convert_dtm_map, parse_dtm_map}, event::{execute_event, Event as GameEvent, Execute}, map::*, object::ObjectInfo, tile::*
     },
     network::net::*,
-    parse::{parse_items, parse_objects, parse_settings, parse_story, parse_units},
+    parse::{
+        parse_items, parse_objects, parse_settings, parse_story, parse_terrains, parse_units,
+    },
     time::time::Data as TimeData,
     units::unit::{ActionResult, Unit, UnitPos},
 };
@@ -216,8 +218,8 @@ fn move_thing(battle: &mut BattleInfo, armys: &mut Vec<Army>) {
             battle.active_unit = battle.search_next_active(armys);
         }
         battle.can_interact = search_interactions(battle, armys);
-    } else {
-        //state.menu_id = Menu::Start as usize;
+    } else if battle.summary.is_none() {
+        battle.end(armys);
     }
 }
 
@@ -849,13 +851,18 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 										let diff = (pos.0 as i64 - goal.0 as i64, pos.1 as i64 - goal.1 as i64);
 										if -1 <= diff.0 && diff.0 <= 1 && -1 <= diff.1 && diff.1 <= 1 {
 											if state.battle.is_none() {
-												let battle = BattleInfo::new(&mut state.gamemap.armys, army, 0);
+												let battle = start_battle(&mut state.gamemap, army, 0);
 												state.battle = Some(battle);
 											}
 											state.menu_id = Menu::Battle as usize;
 											//set_menu_value_num(state, "start_menu", 1);
 										}
 									}
+								} else if let Some(building) = siege_target(&state.gamemap, 0, goal) {
+									if state.battle.is_none() {
+										state.battle = start_siege(&mut state.gamemap, 0, building).ok();
+									}
+									state.menu_id = Menu::Battle as usize;
 								}
 								let path = find_path(&state.gamemap, &state.objects, start, goal, false);
 								state.gamemap.armys[0].path = if let Some(path) = path {
@@ -865,6 +872,10 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 									Vec::new()
 								};
 							}
+							let mut captures = Vec::new();
+							if let Some(battle) = &mut state.battle {
+								captures.extend(end_siege(&mut state.gamemap, battle).ok().flatten());
+							}
 							// grand gameloopa
 							let mut pause = false;
 							for i in 0..1 {
@@ -887,10 +898,23 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 									if let Some(building) = state.gamemap.hitmap[army.pos].building {
 										army.building = Some(building);
 									} else { army.building = None; }
+									captures.extend(state.gamemap.capture(i));
+									state.gamemap.collect_taxes(i);
 									state.gamemap.recalc_armies_hitboxes();
 								}
-								state.gamemap.time.minutes += 10;
-
+								for ledger in state.gamemap.pass_time(10) {
+									if ledger.army == 0 {
+										set_menu_value_num(state, "start_menu", 4);
+										set_menu_value_str(state, "current_message", ledger.report());
+									}
+								}
+							}
+							for capture in captures {
+								for event in capture.events {
+									execute_event(event, &mut state.gamemap, &mut state.gameevents, &state.units, true);
+								}
+							}
+							if !state.pause {
 								for i in 0..state.gameevents.len() {
 									if let Some(executions) = execute_event(i, &mut state.gamemap, &mut state.gameevents, &state.units, false) {
 										for exec in executions {
@@ -901,7 +925,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 												},
 												Execute::StartBattle(army, _) => {
 													if state.battle.is_none() {
-														let battle = BattleInfo::new(&mut state.gamemap.armys, army, 0);
+														let battle = start_battle(&mut state.gamemap, army, 0);
 														state.battle = Some(battle);
 													}
 													set_menu_value_num(state, "start_menu", 1);
@@ -1526,7 +1550,9 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
 								   }
 							)
 							.if_clicked(|_,_,_,_,state: &mut State| {
-								state.gamemap.buildings[0].recruitment.as_mut().unwrap().buy(&mut state.gamemap.armys[0], 0, &state.units).unwrap_or_else(|_| println!("сё пошло по пизде"));
+								if let Err(err) = state.gamemap.recruit(0, 0, &state.units) {
+									println!("Не удалось нанять: {err:?}");
+								}
 							}).build().unwrap()
 						}).collect())
 							.align_direction(Direction::Right)
@@ -1580,6 +1606,14 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                 }
             }
         }
+        if let Some(terrain) = battle.terrain() {
+            draw.text(
+                &state.fonts[0],
+                &*format!("{}: {}", terrain.name, terrain.description),
+            )
+            .color(Color::BLACK)
+            .position(drawing_pos.0, drawing_pos.1 + 500.);
+        }
     }
     #[repr(u64)]
     enum StartSubMenu {
@@ -1883,7 +1917,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
         to: (usize, usize),
     ) -> AnimationTime<AssetsMap> {
         let texture = match res {
-            ActionResult::Move => {
+            ActionResult::Move | ActionResult::Retreat => {
                 |assets: &AssetsMap| assets.get_texture("assets/Window", "buff.png").clone()
             }
             ActionResult::Buff => {
@@ -1899,21 +1933,21 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
                 |assets: &AssetsMap| assets.get_texture("assets/Window", "ranged.png").clone()
             }
         };
-        let line = *MAX_TROOPS / MAX_LINES;
+        let line = *MAX_TROOPS / *MAX_LINES;
         AnimationTime::new(
             Animation::new(
                 MovementChange::new(
                     (
                         (active_unit.0 % line) as f32 * BETWEEN_CELLS + BETWEEN_CELLS / 2. - 25.,
                         ((active_unit.1 as i64).abs() * 500) as f32
-                            + (((to.0 as i64 / line as i64).max(MAX_LINES as i64 - 1)).abs() as f32
+                            + (((to.0 as i64 / line as i64).max(*MAX_LINES as i64 - 1)).abs() as f32
                                 * BETWEEN_CELLS) as f32
                             - 25.,
                     ),
                     (
                         (to.0 % line) as f32 * BETWEEN_CELLS + BETWEEN_CELLS / 2. - 25.,
                         ((to.1 as i64).abs() * 500) as f32
-                            + (((to.0 as i64 / line as i64).max(MAX_LINES as i64 - 1)).abs() as f32
+                            + (((to.0 as i64 / line as i64).max(*MAX_LINES as i64 - 1)).abs() as f32
                                 * BETWEEN_CELLS) as f32
                             - 25.,
                     ),
@@ -1941,7 +1975,7 @@ fn gen_forms(size: (f32, f32)) -> Result<(), String> {
         let gamemap = &state.gamemap;
         let index = active_unit.1;
 
-        active_unit.1 = if active_unit.0 == battle.army1 { 0 } else { 1 };
+        active_unit.1 = battle.side(active_unit.0);
         let active_index: usize = gamemap.armys[active_unit.0].troops[index].get().pos.into();
         active_unit.0 = active_index;
         state
@@ -2351,6 +2385,7 @@ fn gen_army_troops(rng: &mut ThreadRng, units: &Vec<Unit>, army: usize) -> Vec<T
                     unit.army = army;
                     unit
                 },
+                battle_stats: Default::default(),
             }
             .into()
         })
@@ -2374,6 +2409,7 @@ fn gen_army_troops(rng: &mut ThreadRng, units: &Vec<Unit>, army: usize) -> Vec<T
                         unit.army = army;
                         unit
                     },
+                    battle_stats: Default::default(),
                 }
                 .into()
             })
@@ -2565,13 +2601,19 @@ fn setup(app: &mut App, app_assets: &mut Assets, gfx: &mut Graphics) -> State {
         mapa
     });
 
-    let settings = parse_settings();
+    let settings = parse_settings().unwrap_or_else(|err| {
+        log::error!("{}", err);
+        panic!("{}", err);
+    });
 
     app.window().set_fullscreen(settings.fullscreen);
     app.window()
         .set_size(settings.init_size.0, settings.init_size.1);
     let req_assets = parse_items(None, &settings.locale);
     load_assets(gfx, &mut assets, req_assets.1, req_assets.0).expect("Loading items assets failed");
+    for err in parse_terrains(None) {
+        log::error!("{}", err);
+    }
     {
         let locale = &mut LOCALE.lock().unwrap();
         dbg!(&settings);